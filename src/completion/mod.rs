@@ -0,0 +1,89 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::result;
+
+mod openai;
+mod retry;
+mod scripted;
+
+pub use openai::OpenAiBackend;
+pub use retry::RetryingBackend;
+pub use scripted::ScriptedBackend;
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endpoint {
+    // legacy endpoint, takes a flat text prompt
+    Completion,
+    // takes a list of role-tagged messages
+    Chat,
+}
+
+#[derive(Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub content: String,
+}
+
+// the variant determines which endpoint the request is sent to
+#[derive(Clone, Debug)]
+pub enum Prompt {
+    Text(String),
+    Chat(Vec<ChatMessage>),
+}
+
+#[derive(Clone, Debug)]
+pub struct CompletionRequest {
+    pub prompt: Prompt,
+    pub engine: String,
+    pub max_tokens: u64,
+    pub temperature: f64,
+    pub top_p: f64,
+    pub frequency_penalty: f64,
+    pub presence_penalty: f64,
+    // candidates generated server-side, only the best one is returned, ignored by chat models
+    pub best_of: u64,
+    pub stop: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum CompletionError {
+    Api(openai_api::Error),
+    Http(reqwest::Error),
+    // non-success status from the chat endpoint along with the response body
+    Status(u16, String),
+    // the api responded without any choices
+    EmptyCompletion,
+    // a single attempt took longer than the configured timeout
+    Timeout,
+    // the backend failed repeatedly and requests are paused for a while
+    CircuitOpen,
+}
+
+impl From<openai_api::Error> for CompletionError {
+    fn from(e: openai_api::Error) -> Self {
+        Self::Api(e)
+    }
+}
+
+impl From<reqwest::Error> for CompletionError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+#[async_trait]
+pub trait CompletionBackend: Send + Sync {
+    /// returns the completion text, not including the prompt
+    async fn complete(&self, request: CompletionRequest) -> result::Result<String, CompletionError>;
+}
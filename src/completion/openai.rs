@@ -0,0 +1,136 @@
+use crate::completion::{ChatMessage, CompletionBackend, CompletionError, CompletionRequest, Prompt};
+use async_trait::async_trait;
+use openai_api::api::CompletionArgs;
+use openai_api::Client;
+use serde::{Deserialize, Serialize};
+use std::mem;
+
+const CHAT_COMPLETIONS_URL: &str = "https://api.openai.com/v1/chat/completions";
+// the chat endpoint accepts at most this many stop sequences
+const MAX_CHAT_STOP_TOKENS: usize = 4;
+
+#[derive(Serialize)]
+struct ChatCompletionArgs<'a> {
+    model: &'a str,
+    messages: &'a [ChatMessage],
+    max_tokens: u64,
+    temperature: f64,
+    top_p: f64,
+    frequency_penalty: f64,
+    presence_penalty: f64,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct ChatCompletion {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Deserialize)]
+struct ChatChoice {
+    message: ChatChoiceMessage,
+}
+
+#[derive(Deserialize)]
+struct ChatChoiceMessage {
+    content: String,
+}
+
+pub struct OpenAiBackend {
+    client: Client,
+    http: reqwest::Client,
+    token: String,
+}
+
+impl OpenAiBackend {
+    pub fn new(token: &str) -> Self {
+        Self {
+            client: Client::new(token).unwrap(),
+            http: reqwest::Client::new(),
+            token: token.to_string(),
+        }
+    }
+
+    async fn complete_text(
+        &self,
+        prompt: String,
+        request: CompletionRequest,
+    ) -> Result<String, CompletionError> {
+        let mut builder = CompletionArgs::builder();
+        builder
+            .prompt(prompt)
+            .engine(request.engine)
+            .max_tokens(request.max_tokens)
+            .temperature(request.temperature)
+            .top_p(request.top_p)
+            .frequency_penalty(request.frequency_penalty)
+            .presence_penalty(request.presence_penalty)
+            .best_of(request.best_of);
+
+        // the api rejects an empty stop list
+        if !request.stop.is_empty() {
+            builder.stop(request.stop);
+        }
+
+        let args = builder.build().unwrap();
+        let completion = self.client.complete_prompt(args).await?;
+        let choice = completion
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(CompletionError::EmptyCompletion)?;
+        Ok(choice.text)
+    }
+
+    async fn complete_chat(
+        &self,
+        messages: Vec<ChatMessage>,
+        request: CompletionRequest,
+    ) -> Result<String, CompletionError> {
+        let stop = &request.stop[..request.stop.len().min(MAX_CHAT_STOP_TOKENS)];
+        let args = ChatCompletionArgs {
+            model: &request.engine,
+            messages: &messages,
+            max_tokens: request.max_tokens,
+            temperature: request.temperature,
+            top_p: request.top_p,
+            frequency_penalty: request.frequency_penalty,
+            presence_penalty: request.presence_penalty,
+            stop,
+        };
+
+        let response = self
+            .http
+            .post(CHAT_COMPLETIONS_URL)
+            .bearer_auth(&self.token)
+            .json(&args)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(CompletionError::Status(status.as_u16(), body));
+        }
+
+        let completion: ChatCompletion = response.json().await?;
+        let choice = completion
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(CompletionError::EmptyCompletion)?;
+        Ok(choice.message.content)
+    }
+}
+
+#[async_trait]
+impl CompletionBackend for OpenAiBackend {
+    async fn complete(&self, mut request: CompletionRequest) -> Result<String, CompletionError> {
+        // take the prompt out so the rest of the request can be passed along
+        match mem::replace(&mut request.prompt, Prompt::Text(String::new())) {
+            Prompt::Text(prompt) => self.complete_text(prompt, request).await,
+            Prompt::Chat(messages) => self.complete_chat(messages, request).await,
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use crate::completion::{CompletionBackend, CompletionError, CompletionRequest};
+use crate::config::RetryConfig;
+use async_trait::async_trait;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::{sleep, timeout};
+
+impl CompletionError {
+    // worth retrying, and a sign that the backend is having problems rather than the request
+    fn is_transient(&self) -> bool {
+        use CompletionError::*;
+        match self {
+            // only network and protocol failures, errors reported by the api itself (e.g. the prompt
+            // exceeding the context) and bad arguments just fail again and say nothing about
+            // the backend's health
+            Api(openai_api::Error::AsyncProtocol(_)) => true,
+            Api(_) => false,
+            Http(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+            Status(status, _) => *status == 429 || *status >= 500,
+            Timeout => true,
+            EmptyCompletion | CircuitOpen => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Breaker {
+    // requests that failed in a row after all their retries
+    consecutive_failures: u32,
+    // requests are rejected right away until then, afterwards a single request is let through
+    // to check whether the backend recovered
+    open_until: Option<Instant>,
+}
+
+// wraps another backend with a per-attempt timeout, retries with exponential backoff and jitter
+// for transient errors, and a circuit breaker that fails fast while the backend is down
+pub struct RetryingBackend<B> {
+    inner: B,
+    config: RetryConfig,
+    breaker: Mutex<Breaker>,
+}
+
+impl<B: CompletionBackend> RetryingBackend<B> {
+    pub fn new(inner: B, config: RetryConfig) -> Self {
+        Self {
+            inner,
+            config,
+            breaker: Mutex::default(),
+        }
+    }
+
+    // full backoff doubles with each attempt up to the maximum, half of it is randomized so that
+    // chats that failed together don't retry together
+    fn backoff(&self, attempt: u32) -> Duration {
+        let full = self
+            .config
+            .initial_backoff_ms
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.config.max_backoff_ms);
+        let jittered = full / 2 + (rand::random::<f64>() * (full / 2) as f64) as u64;
+        Duration::from_millis(jittered)
+    }
+
+    fn check_breaker(&self) -> Result<(), CompletionError> {
+        let mut breaker = self.breaker.lock().unwrap();
+        match breaker.open_until {
+            Some(open_until) if Instant::now() < open_until => Err(CompletionError::CircuitOpen),
+            Some(_) => {
+                // half-open, keep other requests out while this one is in flight
+                breaker.open_until = Some(
+                    Instant::now() + Duration::from_secs(self.config.breaker_cooldown_secs),
+                );
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record_outcome(&self, result: &Result<String, CompletionError>) {
+        let mut breaker = self.breaker.lock().unwrap();
+        match result {
+            Err(e) if e.is_transient() => {
+                breaker.consecutive_failures += 1;
+                if breaker.consecutive_failures >= self.config.breaker_threshold {
+                    println!(
+                        ">> {} failed completion(s) in a row, pausing requests for {}s",
+                        breaker.consecutive_failures, self.config.breaker_cooldown_secs
+                    );
+                    breaker.open_until = Some(
+                        Instant::now() + Duration::from_secs(self.config.breaker_cooldown_secs),
+                    );
+                }
+            }
+            // the backend answered, even if it didn't like the request, so a chat with a bad
+            // prompt doesn't pause every other chat
+            _ => *breaker = Breaker::default(),
+        }
+    }
+
+    async fn complete_with_retries(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<String, CompletionError> {
+        let mut attempt = 0;
+        loop {
+            let result = match timeout(self.config.timeout(), self.inner.complete(request.clone()))
+                .await
+            {
+                Ok(result) => result,
+                Err(_) => Err(CompletionError::Timeout),
+            };
+
+            match result {
+                Err(e) if e.is_transient() && attempt < self.config.max_retries => {
+                    let backoff = self.backoff(attempt);
+                    println!(
+                        ">> completion attempt {} failed, retrying in {:?}: {:?}",
+                        attempt + 1,
+                        backoff,
+                        e
+                    );
+                    sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<B: CompletionBackend> CompletionBackend for RetryingBackend<B> {
+    async fn complete(&self, request: CompletionRequest) -> Result<String, CompletionError> {
+        self.check_breaker()?;
+        let result = self.complete_with_retries(request).await;
+        self.record_outcome(&result);
+        result
+    }
+}
@@ -0,0 +1,54 @@
+use crate::completion::{CompletionBackend, CompletionError, CompletionRequest, Prompt, Role};
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+// deterministic backend for tests and offline development, replies with the scripted
+// replies in order and echoes the last line of the prompt once they run out
+pub struct ScriptedBackend {
+    replies: Mutex<VecDeque<String>>,
+}
+
+impl ScriptedBackend {
+    pub fn new(replies: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            replies: Mutex::new(replies.into_iter().collect()),
+        }
+    }
+
+    pub fn echo() -> Self {
+        Self::new([])
+    }
+
+    fn echo_reply(prompt: &Prompt) -> String {
+        match prompt {
+            Prompt::Text(prompt) => {
+                // the last line is the bot's reply prefix, echo the message before it
+                let line = prompt.lines().rev().nth(1).unwrap_or_default();
+                match line.split_once(": ") {
+                    Some((_, msg)) => msg.to_string(),
+                    None => line.to_string(),
+                }
+            }
+            Prompt::Chat(messages) => messages
+                .iter()
+                .rev()
+                .find(|m| !matches!(m.role, Role::System))
+                .map(|m| m.content.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[async_trait]
+impl CompletionBackend for ScriptedBackend {
+    async fn complete(&self, request: CompletionRequest) -> Result<String, CompletionError> {
+        let reply = self
+            .replies
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| Self::echo_reply(&request.prompt));
+        Ok(reply)
+    }
+}
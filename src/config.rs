@@ -0,0 +1,140 @@
+use crate::completion::Endpoint;
+use crate::UserId;
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelSpec {
+    // sent to the api as the model/engine name
+    pub id: String,
+    pub display_name: String,
+    // in tokens, including the completion
+    pub max_context: usize,
+    // in dollars
+    pub cost_per_token: f64,
+    pub endpoint: Endpoint,
+}
+
+// how completion requests are retried, see RetryingBackend
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    // per attempt
+    pub timeout_secs: u64,
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // requests that fail in a row before requests are paused
+    pub breaker_threshold: u32,
+    pub breaker_cooldown_secs: u64,
+}
+
+impl RetryConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 30,
+            max_retries: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 8000,
+            breaker_threshold: 5,
+            breaker_cooldown_secs: 60,
+        }
+    }
+}
+
+// usage limits, a limit that is missing from the config or null means unlimited, so that
+// deployments without a limits section keep working as before
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub user_messages_per_minute: Option<u32>,
+    pub chat_tokens_per_day: Option<u64>,
+    // in dollars over all chats, estimated from the models' cost per token
+    pub daily_spend_cap: Option<f64>,
+}
+
+// who may use the settings dialog and the commands marked as restricted
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    // telegram user ids that are allowed everything in every chat
+    pub owners: Vec<UserId>,
+    // limit restricted actions in groups to the chat's admins
+    pub restrict_to_admins: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub default_model: String,
+    pub models: Vec<ModelSpec>,
+    pub retry: RetryConfig,
+    pub limits: LimitsConfig,
+    pub access: AccessConfig,
+}
+
+impl Config {
+    // falls back to the built-in defaults if there is no config file, errors are meant to be
+    // shown to whoever runs the bot
+    pub fn load(path: &str) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(json) => {
+                let config: Self = serde_json::from_str(&json)
+                    .map_err(|e| format!("error parsing config file {}: {}", path, e))?;
+                if config.models.is_empty() {
+                    return Err(format!("config file {} must define at least one model", path));
+                }
+                Ok(config)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                println!("no config file found, using defaults");
+                Ok(Self::default())
+            }
+            Err(e) => Err(format!("error reading config file {}: {}", path, e)),
+        }
+    }
+
+    // unknown ids (e.g. a model that was removed from the config) fall back to the default model
+    pub fn model(&self, id: &str) -> &ModelSpec {
+        self.models
+            .iter()
+            .find(|m| m.id == id)
+            .or_else(|| self.models.iter().find(|m| m.id == self.default_model))
+            .unwrap_or(&self.models[0])
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let model = |id: &str, display_name: &str, max_context, cost_per_token, endpoint| ModelSpec {
+            id: id.to_string(),
+            display_name: display_name.to_string(),
+            max_context,
+            cost_per_token,
+            endpoint,
+        };
+
+        Self {
+            default_model: "davinci".to_string(),
+            models: vec![
+                model("ada", "Ada", 2048, 0.0000004, Endpoint::Completion),
+                model("babbage", "Babbage", 2048, 0.0000005, Endpoint::Completion),
+                model("curie", "Curie", 2048, 0.000002, Endpoint::Completion),
+                model("davinci", "Davinci", 2048, 0.00002, Endpoint::Completion),
+                model("gpt-3.5-turbo", "GPT-3.5 Turbo", 4096, 0.000002, Endpoint::Chat),
+                model("gpt-4", "GPT-4", 8192, 0.00003, Endpoint::Chat),
+            ],
+            retry: RetryConfig::default(),
+            limits: LimitsConfig::default(),
+            access: AccessConfig::default(),
+        }
+    }
+}
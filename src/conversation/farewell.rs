@@ -0,0 +1,91 @@
+use crate::completion::{ChatMessage, CompletionBackend, CompletionRequest, Endpoint, Prompt, Role};
+use crate::conversation::settings::FarewellDetection;
+use crate::conversation::Conversation;
+use crate::result::Result;
+
+const CLASSIFIER_MAX_TOKENS: u64 = 1;
+const CLASSIFIER_TEMPERATURE: f64 = 0.;
+
+// whole-word match so that e.g. "byte" doesn't count as "bye"
+fn contains_keyword(reply: &str, keyword: &str) -> bool {
+    let words = reply
+        .split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    let keyword = keyword
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    !keyword.is_empty() && words.windows(keyword.len()).any(|w| w == keyword)
+}
+
+impl Conversation {
+    fn says_farewell_keyword(&self, reply: &str) -> bool {
+        self.settings
+            .farewell_keywords
+            .iter()
+            .any(|k| contains_keyword(reply, k))
+    }
+
+    // ask the model for a yes/no verdict, anything other than "yes" counts as no
+    async fn classify_farewell(
+        &mut self,
+        reply: &str,
+        backend: &dyn CompletionBackend,
+    ) -> Result<bool> {
+        let instructions = format!(
+            "Does the following chat message say goodbye and end the conversation? \
+            Answer yes or no.\n\nMessage: {}",
+            reply
+        );
+        let prompt = match self.settings.model_spec().endpoint {
+            Endpoint::Completion => Prompt::Text(format!("{}\nAnswer:", instructions)),
+            Endpoint::Chat => Prompt::Chat(vec![ChatMessage {
+                role: Role::User,
+                name: None,
+                content: instructions,
+            }]),
+        };
+        let request = CompletionRequest {
+            prompt,
+            engine: self.settings.model_spec().id.clone(),
+            max_tokens: CLASSIFIER_MAX_TOKENS,
+            temperature: CLASSIFIER_TEMPERATURE,
+            top_p: 1.,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+            best_of: 1,
+            stop: vec![],
+        };
+
+        let prompt = request.prompt.clone();
+        let verdict = backend.complete(request).await?;
+        self.stats.record_usage(&prompt, &verdict);
+        println!(">> farewell classifier says {:?}", verdict);
+        Ok(verdict.trim().to_lowercase().starts_with("yes"))
+    }
+
+    // whether the bot's reply ends the conversation, unless the classifier is used on its own it
+    // is only consulted for replies that passed the keyword check so that it doesn't cost a
+    // request per message, a failed classification counts as no since the reply is sent anyway
+    pub async fn is_farewell(&mut self, reply: &str, backend: &dyn CompletionBackend) -> bool {
+        use FarewellDetection::*;
+        let classify = match self.settings.farewell_detection {
+            Off => return false,
+            Keywords => return self.says_farewell_keyword(reply),
+            Classifier => self.says_farewell_keyword(reply),
+            ClassifierOnly => true,
+        };
+        if !classify {
+            return false;
+        }
+        match self.classify_farewell(reply, backend).await {
+            Ok(farewell) => farewell,
+            Err(e) => {
+                println!(">> farewell classifier failed, assuming no farewell: {:?}", e);
+                false
+            }
+        }
+    }
+}
@@ -1,27 +1,38 @@
-use crate::{ChatId, MessageId};
+use crate::completion::{
+    ChatMessage, CompletionBackend, CompletionRequest, Endpoint, Prompt, Role,
+};
+use crate::{ChatId, MessageId, UserId};
+use futures::lock::Mutex;
 use itertools::Itertools;
-use lazy_static::lazy_static;
-use openai_api::api::CompletionArgs;
-use openai_api::Client;
-use settings::{Model, Settings};
+use participants::{Participant, Speaker};
+use persistence::Storage;
+use serde::{Deserialize, Serialize};
+use settings::{PromptFormat, Settings};
+use stats::Stats;
 use std::collections::hash_map::Entry;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::iter;
-use std::num::NonZeroUsize;
+use std::sync::Arc;
 use teloxide::prelude::*;
 use teloxide::types::User;
 
 use crate::result::{AppError, Result};
 
+mod farewell;
+mod participants;
+pub mod persistence;
+mod repetition;
 pub mod settings;
+mod stats;
+mod summary;
+mod tokens;
 
-const DEFAULT_CONVERSATION_LIMIT: usize = 100;
-const MAX_TOKENS: u64 = 100;
-
-lazy_static! {
-    static ref STOP_TOKENS: Vec<String> = ["\n", ".", "!", "?"].into_iter().map(ToString::to_string).collect();
-    // static ref STOP_TOKENS: Vec<String> = ["\n"].into_iter().map(ToString::to_string).collect();
-}
+// reserved for the reply prefix and the system messages of chat prompts
+pub const PROMPT_OVERHEAD_TOKENS: usize = 50;
+// unsummarized messages are kept up to this many times the model's context
+const MAX_EVICTED_CONTEXTS: usize = 3;
 
 #[derive(Clone)]
 pub enum FromUser {
@@ -29,110 +40,305 @@ pub enum FromUser {
     Myself,
 }
 
-impl FromUser {
-    fn to_name(&self, conversation: &Conversation) -> String {
-        use FromUser::*;
-        match self {
-            User(user) => user.first_name.clone(),
-            Myself => conversation.settings.bot_name.clone(),
+// the chat endpoint only accepts names matching [a-zA-Z0-9_-]{1,64}
+fn sanitize_chat_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .map(|c| if c == ' ' { '_' } else { c })
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .take(64)
+        .collect();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+// for speakers whose name has none of the allowed characters (e.g. names in cyrillic), so that
+// different speakers don't all become the same anonymous user
+fn fallback_chat_name(speaker: &Speaker) -> String {
+    match speaker {
+        Speaker::User(user) => format!("user_{}", user),
+        Speaker::Legacy(name) => {
+            let mut hasher = DefaultHasher::new();
+            name.hash(&mut hasher);
+            format!("user_{:x}", hasher.finish())
         }
     }
 }
 
+// None means the bot, it's rendered with whatever the bot name is when the prompt is generated
+type Entry = (Option<Speaker>, String);
+
+#[derive(Serialize, Deserialize)]
 pub struct Conversation {
-    // in chronological order, None user means bot sent the message
-    messages: VecDeque<(String, String)>,
-    limit: Option<NonZeroUsize>,
-    last_reply: Option<String>,
+    // in chronological order
+    messages: VecDeque<Entry>,
+    // estimated token count of all messages, kept under the settings' history budget
+    #[serde(skip)]
+    history_tokens: usize,
+    // condensed version of the messages that fell out of the history, only used if enabled
+    #[serde(default)]
+    summary: Option<String>,
+    // messages that fell out of the history and haven't been summarized yet
+    #[serde(default)]
+    evicted: Vec<Entry>,
+    // everyone who wrote in the conversation, used to render speaker labels
+    #[serde(default)]
+    participants: HashMap<UserId, Participant>,
+    // the bot's latest replies, oldest first, used to detect repetition
+    #[serde(default)]
+    recent_replies: VecDeque<String>,
+    // incremented for every incoming message, a delayed reply is only sent if no newer message
+    // arrived in the meantime
+    #[serde(skip)]
+    reply_generation: u64,
+    #[serde(default)]
+    pub stats: Stats,
     pub settings: Settings,
     pub active_settings_dialog: Option<(ChatId, MessageId)>,
 }
 
 impl Conversation {
-    // limit = 0 means no limit
-    fn new(limit: usize) -> Self {
+    fn new() -> Self {
         Self {
-            messages: VecDeque::with_capacity(if limit == 0 || limit > 100 {
-                100
-            } else {
-                limit
-            }),
-            limit: NonZeroUsize::new(limit),
-            last_reply: None,
+            messages: VecDeque::new(),
+            history_tokens: 0,
+            summary: None,
+            evicted: vec![],
+            participants: HashMap::new(),
+            recent_replies: VecDeque::with_capacity(repetition::REPLY_WINDOW),
+            reply_generation: 0,
+            stats: Stats::default(),
             settings: Settings::default(),
             active_settings_dialog: None,
         }
     }
 
-    pub fn add(&mut self, from: FromUser, msg: String) {
-        if let Some(limit) = self.limit {
-            if self.messages.len() == limit.get() {
-                self.messages.pop_front();
+    fn entry_tokens(&self, (speaker, msg): &Entry) -> usize {
+        // counts the separator and line break as well
+        tokens::estimate(&self.speaker_name(speaker)) + tokens::estimate(msg) + 2
+    }
+
+    fn active_summary(&self) -> Option<&str> {
+        self.summary
+            .as_deref()
+            .filter(|_| self.settings.summarize_history)
+    }
+
+    // drop messages from the history, keeping them around to be summarized if enabled, if
+    // summarizing keeps failing the oldest ones are dropped for good once they'd take more than
+    // a few summary requests
+    fn evict(&mut self, entries: impl IntoIterator<Item = Entry>) {
+        if !self.settings.summarize_history {
+            return;
+        }
+        self.evicted.extend(entries);
+
+        let limit = MAX_EVICTED_CONTEXTS * self.settings.model_spec().max_context;
+        let mut evicted_tokens: usize = self.evicted.iter().map(|e| self.entry_tokens(e)).sum();
+        let mut dropped = 0;
+        while evicted_tokens > limit && dropped < self.evicted.len() {
+            evicted_tokens -= self.entry_tokens(&self.evicted[dropped]);
+            dropped += 1;
+        }
+        if dropped > 0 {
+            println!(">> dropping {} unsummarized message(s)", dropped);
+            self.evicted.drain(..dropped);
+        }
+    }
+
+    // drop the oldest messages until the history fits the budget, the latest message is always kept
+    fn trim_history(&mut self) {
+        // room for the summary is already reserved by the budget
+        let budget = self.settings.history_token_budget();
+        while self.history_tokens > budget && self.messages.len() > 1 {
+            if let Some(entry) = self.messages.pop_front() {
+                // the count can be stale if the bot was renamed, don't underflow
+                self.history_tokens = self.history_tokens.saturating_sub(self.entry_tokens(&entry));
+                self.evict([entry]);
             }
         }
-        self.messages.push_back((from.to_name(self), msg));
     }
 
-    fn generate_prompt(&self) -> String {
+    // the bot name and speaker labels affect the token count of messages and aren't tracked
+    fn recount_history_tokens(&mut self) {
+        self.history_tokens = self.messages.iter().map(|e| self.entry_tokens(e)).sum();
+    }
+
+    pub fn add(&mut self, from: FromUser, msg: String) {
+        let speaker = match from {
+            FromUser::User(user) => {
+                self.register_participant(&user);
+                self.stats.record_message(user.id);
+                Some(Speaker::User(user.id))
+            }
+            FromUser::Myself => None,
+        };
+        let entry = (speaker, msg);
+        self.history_tokens += self.entry_tokens(&entry);
+        self.messages.push_back(entry);
+        self.trim_history();
+    }
+
+    pub fn next_reply_generation(&mut self) -> u64 {
+        self.reply_generation += 1;
+        self.reply_generation
+    }
+
+    pub fn is_latest_reply_generation(&self, generation: u64) -> bool {
+        self.reply_generation == generation
+    }
+
+    fn generate_flat_prompt(&self) -> String {
         // TODO: keep cached prompt string
-        self.messages
-            .iter()
-            .map(|(user, msg)| format!("{}: {}", user, msg))
-            .chain(iter::once(format!("{}: ", self.settings.bot_name)))
+        let reply_prefix = if self.settings.trailing_space_in_prompt {
+            format!("{}: ", self.settings.bot_name)
+        } else {
+            format!("{}:", self.settings.bot_name)
+        };
+
+        let persona = self
+            .settings
+            .persona
+            .as_ref()
+            .map(|persona| format!("({} - {})", self.settings.bot_name, persona));
+        let summary = self
+            .active_summary()
+            .map(|summary| format!("(summary of the earlier conversation: {})", summary));
+
+        persona
+            .into_iter()
+            .chain(summary)
+            .chain(
+                self.messages
+                    .iter()
+                    .map(|(speaker, msg)| format!("{}: {}", self.speaker_name(speaker), msg)),
+            )
+            .chain(iter::once(reply_prefix))
             .join("\n")
     }
 
-    async fn interact_with_api(&self, prompt: String, client: &Client) -> Result<String> {
-        use Model::*;
-        let engine = match self.settings.model {
-            Ada => "ada",
-            Babbage => "babbage",
-            Curie => "curie",
-            Davinci => "davinci",
+    fn generate_chat_messages(&self) -> Vec<ChatMessage> {
+        let mut content = format!(
+            "You are {}, a participant in a group chat. Reply with a single short message.",
+            self.settings.bot_name
+        );
+        if let Some(persona) = &self.settings.persona {
+            content += &format!("\n{}", persona);
+        }
+        if let Some(summary) = self.active_summary() {
+            content += &format!("\nSummary of the earlier conversation: {}", summary);
+        }
+        let system = ChatMessage {
+            role: Role::System,
+            name: None,
+            content,
         };
 
-        let args = CompletionArgs::builder()
-            .prompt(prompt)
-            .engine(engine)
-            .max_tokens(MAX_TOKENS)
-            .temperature(self.settings.temperature)
-            .stop(STOP_TOKENS.clone())
-            .build()
-            .unwrap();
+        let history = self.messages.iter().map(|(speaker, msg)| match speaker {
+            Some(_) => ChatMessage {
+                role: Role::User,
+                name: sanitize_chat_name(&self.speaker_name(speaker))
+                    .or_else(|| speaker.as_ref().map(fallback_chat_name)),
+                content: msg.clone(),
+            },
+            None => ChatMessage {
+                role: Role::Assistant,
+                name: None,
+                content: msg.clone(),
+            },
+        });
 
-        let reply = client.complete_prompt(args).await?.choices[0]
-            .text
-            .trim_start()
-            .to_string();
+        iter::once(system).chain(history).collect()
+    }
 
-        Ok(reply)
+    fn generate_prompt(&self) -> Prompt {
+        match (self.settings.model_spec().endpoint, self.settings.prompt_format) {
+            (Endpoint::Completion, _) => Prompt::Text(self.generate_flat_prompt()),
+            (Endpoint::Chat, PromptFormat::Chat) => Prompt::Chat(self.generate_chat_messages()),
+            (Endpoint::Chat, PromptFormat::Flat) => {
+                // chat models need messages, pass the flat transcript and ask for a continuation
+                let instructions = ChatMessage {
+                    role: Role::System,
+                    name: None,
+                    content: format!(
+                        "Continue the chat transcript with the next message from {}. \
+                        Only write the message text.",
+                        self.settings.bot_name
+                    ),
+                };
+                let transcript = ChatMessage {
+                    role: Role::User,
+                    name: None,
+                    content: self.generate_flat_prompt(),
+                };
+                Prompt::Chat(vec![instructions, transcript])
+            }
+        }
     }
 
-    pub async fn produce_reply(&mut self, client: &Client) -> Result<String> {
-        let prompt = self.generate_prompt();
-        println!(">> sending prompt:\n{:?}", prompt);
-        let mut reply = self.interact_with_api(prompt, client).await?;
-        println!(">> received reply: {:?}", reply);
+    fn completion_request(&self, prompt: Prompt) -> CompletionRequest {
+        CompletionRequest {
+            prompt,
+            engine: self.settings.model_spec().id.clone(),
+            max_tokens: self.settings.max_tokens,
+            temperature: self.settings.temperature,
+            top_p: self.settings.top_p,
+            frequency_penalty: self.settings.frequency_penalty,
+            presence_penalty: self.settings.presence_penalty,
+            best_of: self.settings.best_of,
+            stop: self.settings.stop_tokens.clone(),
+        }
+    }
 
-        if let Some(last_reply) = &self.last_reply {
-            if &reply == last_reply {
-                println!(">> same as last reply, clear and try again");
-                self.messages.drain(0..self.messages.len() - 1);
-                let prompt = self.generate_prompt();
-                println!(">> sending prompt:\n\"{:?}\"", prompt);
-                reply = self.interact_with_api(prompt, client).await?;
-                println!(">> received reply: \"{:?}\"", reply);
-            }
+    async fn interact_with_api(
+        &mut self,
+        request: CompletionRequest,
+        backend: &dyn CompletionBackend,
+    ) -> Result<String> {
+        let prompt = request.prompt.clone();
+        let reply = backend.complete(request).await?.trim_start().to_string();
+        self.stats.record_usage(&prompt, &reply);
+        Ok(reply)
+    }
+
+    pub async fn produce_reply(&mut self, backend: &dyn CompletionBackend) -> Result<String> {
+        // settings might have changed the budget since the last message
+        self.recount_history_tokens();
+        self.trim_history();
+        if self.settings.summarize_history {
+            self.update_summary(backend).await;
         }
 
-        self.last_reply = Some(reply.clone());
+        let request = self.completion_request(self.generate_prompt());
+        println!(">> sending prompt:\n{:?}", request.prompt);
+        let reply = self.interact_with_api(request, backend).await?;
+        println!(">> received reply: {:?}", reply);
+
+        let reply = self.recover_from_repetition(reply, backend).await?;
+        self.remember_reply(reply.clone());
+        self.stats.replies += 1;
 
         self.add(FromUser::Myself, reply.clone());
         Ok(reply)
     }
 
-    pub fn clear_history(&mut self) {
-        self.messages.clear();
+    // forget everything but the latest messages, including the summary
+    pub fn clear_history(&mut self, keep_last: usize) {
+        let drop_until = self.messages.len().saturating_sub(keep_last);
+        self.messages.drain(..drop_until);
+        self.recount_history_tokens();
+        self.summary = None;
+        self.evicted.clear();
+    }
+
+    pub fn get_status_text(&self) -> String {
+        self.stats.get_message_text(
+            |user| self.participant_label(user),
+            self.settings.model_spec().cost_per_token,
+        )
     }
 
     pub async fn deactivate_settings_dialog(&mut self, requester: &Bot) -> Result {
@@ -163,42 +369,231 @@ impl Conversation {
     }
 }
 
-pub struct Conversations(HashMap<ChatId, Conversation>);
+// each conversation has its own lock so that chats are processed in parallel, the outer lock
+// should only be held long enough to look up or insert/remove a conversation, and never while
+// waiting for a conversation's lock
+#[derive(Default)]
+pub struct Conversations(HashMap<ChatId, Arc<Mutex<Conversation>>>);
 
 impl Conversations {
-    pub fn new() -> Self {
-        Self(HashMap::new())
+    // restore the conversations that were running when the bot was last stopped
+    pub fn restore(storage: &dyn Storage) -> Result<Self> {
+        let conversations: HashMap<_, _> = storage
+            .load_all()?
+            .into_iter()
+            .map(|(chat, mut conversation)| {
+                conversation.recount_history_tokens();
+                (chat, Arc::new(Mutex::new(conversation)))
+            })
+            .collect();
+        println!("restored {} conversation(s)", conversations.len());
+        Ok(Self(conversations))
     }
 
-    pub fn begin(&mut self, chat: ChatId) -> Result {
+    pub fn begin(&mut self, chat: ChatId) -> Result<Arc<Mutex<Conversation>>> {
         match self.0.entry(chat) {
             Entry::Occupied(_) => Err(AppError::ConversationAlreadyRunning(chat))?,
             Entry::Vacant(entry) => {
-                entry.insert(Conversation::new(DEFAULT_CONVERSATION_LIMIT));
-                Ok(())
+                let conversation = Conversation::new();
+                Ok(entry.insert(Arc::new(Mutex::new(conversation))).clone())
             }
         }
     }
 
-    pub fn end(&mut self, chat: ChatId) -> Result {
-        match self.0.entry(chat) {
-            Entry::Occupied(entry) => {
-                entry.remove();
+    // the snapshot is removed under the same lock as the conversation so that a handler that
+    // still holds the conversation can't save it again afterwards, see save
+    pub fn end(&mut self, chat: ChatId, storage: &dyn Storage) -> Result {
+        match self.0.remove(&chat) {
+            Some(_) => storage.remove(chat),
+            None => Err(AppError::NoConversationRunning(chat))?,
+        }
+    }
+
+    // like end, but leaves a conversation that was begun after the given one alone
+    pub fn end_if_current(
+        &mut self,
+        chat: ChatId,
+        handle: &Arc<Mutex<Conversation>>,
+        storage: &dyn Storage,
+    ) -> Result {
+        match self.0.get(&chat) {
+            Some(current) if Arc::ptr_eq(current, handle) => self.end(chat, storage),
+            _ => Err(AppError::NoConversationRunning(chat))?,
+        }
+    }
+
+    // handlers can still hold a conversation after it was ended, and a new one might have been
+    // begun in the same chat since, snapshots are only written for the conversation that is
+    // currently running so that neither case is overwritten, callers hold the conversation's
+    // lock, which is always taken before the lock on the map
+    pub fn save(
+        &self,
+        chat: ChatId,
+        handle: &Arc<Mutex<Conversation>>,
+        conversation: &Conversation,
+        storage: &dyn Storage,
+    ) -> Result {
+        match self.0.get(&chat) {
+            Some(current) if Arc::ptr_eq(current, handle) => storage.save(chat, conversation),
+            _ => {
+                println!("conversation in chat {} was ended, not saving", chat);
                 Ok(())
             }
-            Entry::Vacant(_) => Err(AppError::NoConversationRunning(chat))?,
         }
     }
 
-    pub fn get_mut(&mut self, chat: ChatId) -> Option<&mut Conversation> {
-        self.0.get_mut(&chat)
+    pub fn get(&self, chat: ChatId) -> Option<Arc<Mutex<Conversation>>> {
+        self.0.get(&chat).cloned()
     }
 
-    // called before terminating the bot
-    pub async fn cleanup(&mut self, requester: &Bot) -> Result {
-        for conversation in self.0.values_mut() {
-            conversation.deactivate_settings_dialog(requester).await?;
+    // called before terminating the bot with the conversations taken out of the map, so that
+    // waiting for handlers to finish doesn't hold the map's lock, every conversation is saved
+    // even if others fail (e.g. because their settings message was deleted), the first error is
+    // returned at the end
+    pub async fn cleanup(self, requester: &Bot, storage: &dyn Storage) -> Result {
+        let mut first_error = None;
+        for (&chat, conversation) in self.0.iter() {
+            let mut conversation = conversation.lock().await;
+            let result: Result = try {
+                // the dialog is closed locally even if editing the message fails
+                let deactivated = conversation.deactivate_settings_dialog(requester).await;
+                storage.save(chat, &conversation)?;
+                deactivated?
+            };
+            if let Err(e) = result {
+                println!("failed to clean up conversation in chat {}: {:?}", chat, e);
+                first_error.get_or_insert(e);
+            }
         }
-        Ok(())
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::completion::ScriptedBackend;
+
+    fn user(id: UserId, first_name: &str) -> User {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "is_bot": false,
+            "first_name": first_name,
+        }))
+        .unwrap()
+    }
+
+    // a conversation on a completion model so that the prompt is flat text
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new();
+        conversation.settings.model = "davinci".to_string();
+        conversation.settings.bot_name = "Bot".to_string();
+        conversation.add(FromUser::User(user(1, "Alice")), "hello".to_string());
+        conversation
+    }
+
+    fn request(conversation: &Conversation) -> CompletionRequest {
+        conversation.completion_request(conversation.generate_prompt())
+    }
+
+    fn prompt_text(request: &CompletionRequest) -> &str {
+        match &request.prompt {
+            Prompt::Text(text) => text,
+            Prompt::Chat(_) => panic!("expected a flat prompt"),
+        }
+    }
+
+    #[test]
+    fn trailing_space_changes_reply_prefix() {
+        let mut conversation = conversation();
+
+        conversation.settings.trailing_space_in_prompt = true;
+        assert_eq!(prompt_text(&request(&conversation)), "Alice: hello\nBot: ");
+
+        conversation.settings.trailing_space_in_prompt = false;
+        assert_eq!(prompt_text(&request(&conversation)), "Alice: hello\nBot:");
+    }
+
+    #[test]
+    fn custom_stop_tokens_are_sent() {
+        let mut conversation = conversation();
+        conversation.settings.stop_tokens = vec!["\n".to_string(), "END".to_string()];
+        assert_eq!(request(&conversation).stop, ["\n", "END"]);
+
+        conversation.settings.stop_tokens.clear();
+        assert!(request(&conversation).stop.is_empty());
+    }
+
+    #[test]
+    fn bot_name_labels_replies_and_prefix() {
+        let mut conversation = conversation();
+        conversation.settings.trailing_space_in_prompt = false;
+        conversation.add(FromUser::Myself, "hi Alice".to_string());
+
+        conversation.settings.bot_name = "Robo".to_string();
+        assert_eq!(
+            prompt_text(&request(&conversation)),
+            "Alice: hello\nRobo: hi Alice\nRobo:"
+        );
+    }
+
+    #[test]
+    fn sampling_settings_are_sent() {
+        let mut conversation = conversation();
+        conversation.settings.temperature = 0.3;
+        conversation.settings.max_tokens = 42;
+        conversation.settings.model = "curie".to_string();
+
+        let request = request(&conversation);
+        assert_eq!(request.temperature, 0.3);
+        assert_eq!(request.max_tokens, 42);
+        assert_eq!(request.engine, "curie");
+    }
+
+    #[tokio::test]
+    async fn produce_reply_records_the_reply() {
+        let mut conversation = conversation();
+        let backend = ScriptedBackend::new(["hi Alice".to_string()]);
+
+        let reply = conversation.produce_reply(&backend).await.unwrap();
+        assert_eq!(reply, "hi Alice");
+        assert_eq!(
+            conversation.messages.back(),
+            Some(&(None, "hi Alice".to_string()))
+        );
+        assert_eq!(conversation.messages.len(), 2);
+        assert_eq!(conversation.stats.replies, 1);
+    }
+
+    #[tokio::test]
+    async fn produce_reply_echoes_once_the_script_runs_out() {
+        let mut conversation = conversation();
+        let backend = ScriptedBackend::echo();
+
+        assert_eq!(conversation.produce_reply(&backend).await.unwrap(), "hello");
+        assert_eq!(conversation.stats.replies, 1);
+        assert!(conversation.stats.tokens_used > 0);
+    }
+
+    #[test]
+    fn non_latin_names_stay_apart_in_chat_prompts() {
+        let mut conversation = conversation();
+        conversation.settings.model = "gpt-4".to_string();
+        conversation.settings.prompt_format = PromptFormat::Chat;
+        conversation.add(FromUser::User(user(2, "Алекс")), "привет".to_string());
+        conversation.add(FromUser::User(user(3, "Мария")), "привет".to_string());
+
+        let messages = match request(&conversation).prompt {
+            Prompt::Chat(messages) => messages,
+            Prompt::Text(_) => panic!("expected a chat prompt"),
+        };
+        let names = messages.iter().map(|m| m.name.as_deref()).collect_vec();
+        assert_eq!(
+            names,
+            [None, Some("Alice"), Some("user_2"), Some("user_3")]
+        );
     }
 }
@@ -0,0 +1,103 @@
+use crate::conversation::settings::SpeakerLabels;
+use crate::conversation::Conversation;
+use crate::UserId;
+use serde::{Deserialize, Serialize};
+use teloxide::types::User;
+
+// who sent a message in the history, the bot's own messages have no speaker
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Speaker {
+    User(UserId),
+    // conversations saved before users were tracked by id stored the rendered name
+    Legacy(String),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Participant {
+    pub first_name: String,
+    pub last_name: Option<String>,
+    pub username: Option<String>,
+    // set by the user with /nick, takes precedence over the telegram names
+    pub nickname: Option<String>,
+    // position in the order participants were first seen, keeps disambiguation stable
+    seen_order: usize,
+}
+
+impl Participant {
+    fn label(&self, style: SpeakerLabels) -> String {
+        if let Some(nickname) = &self.nickname {
+            return nickname.clone();
+        }
+        match (style, &self.last_name, &self.username) {
+            (SpeakerLabels::FullName, Some(last_name), _) => {
+                format!("{} {}", self.first_name, last_name)
+            }
+            (SpeakerLabels::Username, _, Some(username)) => username.clone(),
+            _ => self.first_name.clone(),
+        }
+    }
+}
+
+impl Conversation {
+    // names can change between messages, keep the latest ones
+    pub(super) fn register_participant(&mut self, user: &User) {
+        let seen_order = self.participants.len();
+        let participant = self
+            .participants
+            .entry(user.id)
+            .or_insert_with(|| Participant {
+                first_name: String::new(),
+                last_name: None,
+                username: None,
+                nickname: None,
+                seen_order,
+            });
+        participant.first_name = user.first_name.clone();
+        participant.last_name = user.last_name.clone();
+        participant.username = user.username.clone();
+    }
+
+    // None clears the nickname
+    pub fn set_nickname(&mut self, user: &User, nickname: Option<String>) {
+        self.register_participant(user);
+        if let Some(participant) = self.participants.get_mut(&user.id) {
+            participant.nickname = nickname;
+        }
+    }
+
+    // unique among the participants and distinct from the bot name, participants with the same
+    // label are numbered in the order they were first seen, the first one keeps the plain label
+    // unless it clashes with the bot
+    pub fn participant_label(&self, user: UserId) -> String {
+        let style = self.settings.speaker_labels;
+        let participant = match self.participants.get(&user) {
+            Some(participant) => participant,
+            None => return user.to_string(),
+        };
+        let label = participant.label(style);
+
+        let rank = self
+            .participants
+            .values()
+            .filter(|p| p.seen_order < participant.seen_order)
+            .filter(|p| p.label(style).eq_ignore_ascii_case(&label))
+            .count();
+        if label.eq_ignore_ascii_case(&self.settings.bot_name) {
+            format!("{} {}", label, rank + 2)
+        } else if rank > 0 {
+            format!("{} {}", label, rank + 1)
+        } else {
+            label
+        }
+    }
+
+    // how the speaker of a history entry appears in prompts
+    pub(super) fn speaker_name(&self, speaker: &Option<Speaker>) -> String {
+        match speaker {
+            Some(Speaker::User(user)) => self.participant_label(*user),
+            Some(Speaker::Legacy(name)) => name.clone(),
+            None => self.settings.bot_name.clone(),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use crate::conversation::Conversation;
+use crate::result::Result;
+use crate::ChatId;
+use serde::Serialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+pub trait Storage: Send + Sync {
+    fn load_all(&self) -> Result<Vec<(ChatId, Conversation)>>;
+    fn save(&self, chat: ChatId, conversation: &Conversation) -> Result;
+    fn remove(&self, chat: ChatId) -> Result;
+}
+
+// writes to a temporary file first so that a crash mid-write doesn't corrupt the existing file
+pub fn write_json_atomically(path: &Path, value: &impl Serialize) -> Result {
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_string(value)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// one json file per chat, named after the chat id
+pub struct JsonFileStorage {
+    dir: PathBuf,
+}
+
+impl JsonFileStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, chat: ChatId) -> PathBuf {
+        self.dir.join(format!("{}.json", chat))
+    }
+}
+
+impl Storage for JsonFileStorage {
+    fn load_all(&self) -> Result<Vec<(ChatId, Conversation)>> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut conversations = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+
+            let chat = match path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<ChatId>().ok())
+            {
+                Some(chat) => chat,
+                None => {
+                    println!("skipping unexpected file {:?}", path);
+                    continue;
+                }
+            };
+
+            // a single corrupted snapshot shouldn't prevent the others from loading
+            let conversation: Result<Conversation> = try {
+                let json = fs::read_to_string(&path)?;
+                serde_json::from_str(&json)?
+            };
+            match conversation {
+                Ok(conversation) => conversations.push((chat, conversation)),
+                Err(e) => println!("failed to load {:?}: {:?}", path, e),
+            }
+        }
+
+        Ok(conversations)
+    }
+
+    fn save(&self, chat: ChatId, conversation: &Conversation) -> Result {
+        fs::create_dir_all(&self.dir)?;
+
+        write_json_atomically(&self.path(chat), conversation)
+    }
+
+    fn remove(&self, chat: ChatId) -> Result {
+        match fs::remove_file(self.path(chat)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e)?,
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::completion::CompletionBackend;
+use crate::conversation::settings::RepetitionRecovery;
+use crate::conversation::Conversation;
+use crate::result::Result;
+use itertools::Itertools;
+use std::collections::HashSet;
+
+// how many of the bot's latest replies a new reply is compared against
+pub const REPLY_WINDOW: usize = 5;
+const SIMILARITY_THRESHOLD: f64 = 0.8;
+// short replies like "ok" or "yes" are expected to repeat and aren't worth another request
+const MIN_WORDS: usize = 4;
+
+const TEMPERATURE_BOOST: f64 = 0.4;
+const MAX_TEMPERATURE: f64 = 2.;
+const FREQUENCY_PENALTY: f64 = 1.;
+const PRESENCE_PENALTY: f64 = 0.6;
+
+// lowercase words without punctuation, so that "Hi!" and "hi" are considered the same
+fn normalize(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// jaccard similarity of the normalized word sets, between 0 and 1
+fn similarity(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 1.;
+    }
+    a.intersection(b).count() as f64 / a.union(b).count() as f64
+}
+
+fn is_similar(a: &str, b: &str) -> bool {
+    let (a, b) = (normalize(a), normalize(b));
+    a.len() >= MIN_WORDS && b.len() >= MIN_WORDS && similarity(&a, &b) >= SIMILARITY_THRESHOLD
+}
+
+impl Conversation {
+    fn is_repetitive(&self, reply: &str) -> bool {
+        self.recent_replies.iter().any(|r| is_similar(r, reply))
+    }
+
+    pub(super) fn remember_reply(&mut self, reply: String) {
+        if self.recent_replies.len() == REPLY_WINDOW {
+            self.recent_replies.pop_front();
+        }
+        self.recent_replies.push_back(reply);
+    }
+
+    // remove the bot's own messages that are similar to the reply or to another of its recent
+    // replies, leaving everyone else's, a turn that is itself one of the recent replies doesn't
+    // count as repeating itself
+    fn drop_repetitive_bot_turns(&mut self, reply: &str) {
+        let before = self.messages.len();
+        let recent_replies = &self.recent_replies;
+        self.messages.retain(|(speaker, msg)| {
+            let itself = usize::from(recent_replies.contains(msg) && is_similar(msg, msg));
+            let similar = recent_replies.iter().filter(|r| is_similar(msg, r)).count();
+            let repetitive = is_similar(msg, reply) || similar > itself;
+            speaker.is_some() || !repetitive
+        });
+        println!(">> dropped {} repetitive bot turn(s)", before - self.messages.len());
+        self.recount_history_tokens();
+    }
+
+    // escalate through the recovery steps up to the one selected in the settings, retrying after
+    // each until the reply isn't a repetition anymore, the last reply is returned either way
+    pub(super) async fn recover_from_repetition(
+        &mut self,
+        mut reply: String,
+        backend: &dyn CompletionBackend,
+    ) -> Result<String> {
+        use RepetitionRecovery::*;
+
+        let mut temperature = self.settings.temperature;
+        let mut penalties = (
+            self.settings.frequency_penalty,
+            self.settings.presence_penalty,
+        );
+
+        // copied out so that the loop doesn't borrow self
+        let max_step = self.settings.repetition_recovery;
+        let steps = [RaiseTemperature, Penalize, DropBotTurns, ClearHistory];
+        for step in steps.into_iter().take_while(|&step| step <= max_step) {
+            if !self.is_repetitive(&reply) {
+                break;
+            }
+
+            println!(">> repetitive reply, recovering with {:?}", step);
+            match step {
+                RaiseTemperature => {
+                    temperature = (temperature + TEMPERATURE_BOOST).min(MAX_TEMPERATURE)
+                }
+                Penalize => {
+                    penalties = (
+                        penalties.0.max(FREQUENCY_PENALTY),
+                        penalties.1.max(PRESENCE_PENALTY),
+                    )
+                }
+                DropBotTurns => self.drop_repetitive_bot_turns(&reply),
+                ClearHistory => {
+                    let keep_from = self.messages.len().saturating_sub(1);
+                    let dropped = self.messages.drain(..keep_from).collect_vec();
+                    self.evict(dropped);
+                    self.recount_history_tokens();
+                    if self.settings.summarize_history {
+                        self.update_summary(backend).await;
+                    }
+                }
+                Off => unreachable!(),
+            }
+
+            let mut request = self.completion_request(self.generate_prompt());
+            request.temperature = temperature;
+            (request.frequency_penalty, request.presence_penalty) = penalties;
+            println!(">> sending prompt:\n{:?}", request.prompt);
+            reply = self.interact_with_api(request, backend).await?;
+            println!(">> received reply: {:?}", reply);
+        }
+
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::completion::ScriptedBackend;
+    use crate::conversation::participants::Speaker;
+
+    const REPEATED: &str = "I really like talking about the weather with you";
+
+    #[test]
+    fn normalize_ignores_case_and_punctuation() {
+        let expected: HashSet<String> = ["hi", "there"].iter().map(ToString::to_string).collect();
+        assert_eq!(normalize("Hi, hi! HI... there?"), expected);
+    }
+
+    #[test]
+    fn similar_replies_are_detected() {
+        assert!(is_similar(REPEATED, "i really LIKE talking about the weather with you!"));
+        assert!(!is_similar(REPEATED, "Let's talk about something else for a change"));
+    }
+
+    #[test]
+    fn short_replies_are_never_similar() {
+        assert!(!is_similar("ok", "ok"));
+        assert!(!is_similar("yes I do", "yes I do"));
+        assert!(is_similar("yes I really do", "yes I really do"));
+    }
+
+    // a conversation where the bot already said REPEATED and something unrelated
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new();
+        conversation.settings.model = "davinci".to_string();
+        let user = Some(Speaker::Legacy("Alice".to_string()));
+        for (speaker, msg) in [
+            (user.clone(), "how are you"),
+            (None, REPEATED),
+            (user, "nice"),
+            (None, "Tell me more about your day at work"),
+        ] {
+            conversation.messages.push_back((speaker.clone(), msg.to_string()));
+            if speaker.is_none() {
+                conversation.remember_reply(msg.to_string());
+            }
+        }
+        conversation
+    }
+
+    #[test]
+    fn only_repetitive_bot_turns_are_dropped() {
+        let mut conversation = conversation();
+        conversation.drop_repetitive_bot_turns(REPEATED);
+        let remaining = conversation.messages.iter().map(|(_, msg)| msg.as_str()).collect_vec();
+        assert_eq!(
+            remaining,
+            ["how are you", "nice", "Tell me more about your day at work"]
+        );
+    }
+
+    #[tokio::test]
+    async fn recovery_stops_at_the_selected_step() {
+        let mut conversation = conversation();
+        conversation.settings.repetition_recovery = RepetitionRecovery::RaiseTemperature;
+        let backend = ScriptedBackend::new([REPEATED.to_string(), "something new".to_string()]);
+        let reply = conversation
+            .recover_from_repetition(REPEATED.to_string(), &backend)
+            .await
+            .unwrap();
+        // a single retry, which repeated itself again
+        assert_eq!(reply, REPEATED);
+    }
+
+    #[tokio::test]
+    async fn recovery_escalates_until_the_reply_is_new() {
+        let mut conversation = conversation();
+        conversation.settings.repetition_recovery = RepetitionRecovery::ClearHistory;
+        let backend = ScriptedBackend::new([REPEATED.to_string(), "something new".to_string()]);
+        let reply = conversation
+            .recover_from_repetition(REPEATED.to_string(), &backend)
+            .await
+            .unwrap();
+        assert_eq!(reply, "something new");
+        // the second step was enough, the history is untouched
+        assert_eq!(conversation.messages.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn recovery_is_skipped_when_off() {
+        let mut conversation = conversation();
+        conversation.settings.repetition_recovery = RepetitionRecovery::Off;
+        let backend = ScriptedBackend::new(["something new".to_string()]);
+        let reply = conversation
+            .recover_from_repetition(REPEATED.to_string(), &backend)
+            .await
+            .unwrap();
+        assert_eq!(reply, REPEATED);
+    }
+}
@@ -1,78 +1,362 @@
+use crate::completion::Endpoint;
+use crate::config::ModelSpec;
+use crate::conversation::summary::SUMMARY_MAX_TOKENS;
+use crate::conversation::{tokens, PROMPT_OVERHEAD_TOKENS};
+use crate::CONFIG;
 use itertools::Itertools;
+use numeric::{BEST_OF, FREQUENCY_PENALTY, MAX_TOKENS, PRESENCE_PENALTY, TEMPERATURE, TOP_P};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fmt::Debug;
 use teloxide::types::{InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup};
 
-#[derive(Copy, Clone, Debug)]
-pub enum Model {
-    // in increasing order of power
-    Ada,
-    Babbage,
-    Curie,
-    Davinci,
+pub mod numeric;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PromptFormat {
+    // the whole history as "name: message" lines
+    Flat,
+    // role-tagged messages, only supported by chat models
+    Chat,
+}
+
+// recovery steps for when the bot repeats itself, in the order they're tried, each setting
+// also allows all of the steps before it
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum RepetitionRecovery {
+    Off,
+    // retry with a higher temperature
+    RaiseTemperature,
+    // retry with frequency and presence penalties
+    Penalize,
+    // remove the bot's repetitive messages from the history
+    DropBotTurns,
+    // remove everything but the latest message from the history
+    ClearHistory,
 }
 
+// how the bot decides that its own reply ends the conversation
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FarewellDetection {
+    Off,
+    // the reply contains one of the farewell keywords
+    Keywords,
+    // keyword matches are confirmed by asking the model, avoids ending on e.g. "bye for now"
+    Classifier,
+    // every reply is judged by the model, catches farewells without keywords but costs a
+    // request per reply
+    ClassifierOnly,
+}
+
+// which messages the bot replies to in group chats, private chats always get a reply
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ReplyPolicy {
+    Always,
+    // the message contains the bot name or its @username
+    Mentioned,
+    // the message is a reply to one of the bot's messages
+    RepliedTo,
+    // each message gets a reply with the configured probability
+    Random,
+}
+
+// how participants are named in the prompt, users with a nickname always use the nickname and
+// users without a last name or username fall back to their first name
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SpeakerLabels {
+    FirstName,
+    FullName,
+    Username,
+}
+
+// (name, preamble) pairs selectable from the persona editor
+pub const PERSONAS: &[(&str, &str)] = &[
+    (
+        "friendly",
+        "You are warm, curious and supportive, and you like asking people about themselves.",
+    ),
+    (
+        "sarcastic",
+        "You are dry and sarcastic, but never mean, and you keep your replies short.",
+    ),
+    (
+        "pirate",
+        "You are an old pirate captain who talks like one and steers every topic towards the sea.",
+    ),
+    (
+        "philosopher",
+        "You are a thoughtful philosopher who answers questions with deeper questions.",
+    ),
+];
+
+// render a stop token the way it's shown on buttons, newlines are shown as \n
+pub fn format_stop_token(token: &str) -> String {
+    if token == "\n" {
+        "\\n".to_string()
+    } else {
+        format!("\"{}\"", token)
+    }
+}
+
+// inverse of the escaping users see, so that typing \n adds a newline stop token
+pub fn parse_stop_token(text: &str) -> String {
+    text.replace("\\n", "\n").replace("\\t", "\t")
+}
+
+// settings saved before models came from the config stored the name of an enum variant, map
+// those to the ids of the built-in models
+fn deserialize_model<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let model = String::deserialize(deserializer)?;
+    let id = match model.as_str() {
+        "Ada" => "ada",
+        "Babbage" => "babbage",
+        "Curie" => "curie",
+        "Davinci" => "davinci",
+        "Gpt35Turbo" => "gpt-3.5-turbo",
+        "Gpt4" => "gpt-4",
+        _ => return Ok(model),
+    };
+    Ok(id.to_string())
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
-    pub model: Model,
+    // id of a model from the config
+    #[serde(deserialize_with = "deserialize_model")]
+    pub model: String,
+    pub prompt_format: PromptFormat,
     pub bot_name: String,
     pub temperature: f64,
+    // length limit of a reply, also reserved in the prompt
+    pub max_tokens: u64,
+    pub top_p: f64,
+    pub frequency_penalty: f64,
+    pub presence_penalty: f64,
+    pub best_of: u64,
     pub trailing_space_in_prompt: bool,
     pub stop_tokens: Vec<String>,
+    // condense messages that fall out of the history into a summary that's kept in the prompt
+    pub summarize_history: bool,
+    pub repetition_recovery: RepetitionRecovery,
+    // quiet period to wait for more messages before replying, 0 replies to every message
+    pub reply_delay_secs: u64,
+    pub farewell_detection: FarewellDetection,
+    // lowercase words or phrases, matched against whole words of the reply
+    pub farewell_keywords: Vec<String>,
+    pub reply_policy: ReplyPolicy,
+    // only used by the random reply policy, between 0 and 1
+    pub reply_probability: f64,
+    pub speaker_labels: SpeakerLabels,
+    // describes who the bot is and how it behaves, placed at the start of every prompt
+    pub persona: Option<String>,
 }
 
 impl Settings {
-    const DEFAULT_MODEL: Model = Model::Davinci;
+    // a common word, so it doesn't count as a mention of the bot
+    pub const DEFAULT_BOT_NAME: &'static str = "You";
+    const DEFAULT_PROMPT_FORMAT: PromptFormat = PromptFormat::Flat;
     const DEFAULT_TEMPERATURE: f64 = 0.8;
+    const DEFAULT_MAX_TOKENS: u64 = 100;
+    const DEFAULT_TOP_P: f64 = 1.;
+    const DEFAULT_FREQUENCY_PENALTY: f64 = 0.;
+    const DEFAULT_PRESENCE_PENALTY: f64 = 0.;
+    const DEFAULT_BEST_OF: u64 = 1;
     const DEFAULT_TRAILING_SPACE: bool = true;
     const DEFAULT_STOP_TOKENS: &'static [&'static str] = &["\n", ".", "!", "?"];
+    const DEFAULT_SUMMARIZE_HISTORY: bool = false;
+    const DEFAULT_REPETITION_RECOVERY: RepetitionRecovery = RepetitionRecovery::DropBotTurns;
+    const DEFAULT_REPLY_DELAY_SECS: u64 = 0;
+    const REPLY_DELAY_PRESETS: &'static [u64] = &[0, 2, 5, 10, 30];
+    const DEFAULT_FAREWELL_DETECTION: FarewellDetection = FarewellDetection::Off;
+    const DEFAULT_REPLY_POLICY: ReplyPolicy = ReplyPolicy::Always;
+    const DEFAULT_REPLY_PROBABILITY: f64 = 0.3;
+    const REPLY_PROBABILITY_PRESETS: &'static [f64] = &[0.1, 0.2, 0.3, 0.5, 0.8];
+    const DEFAULT_SPEAKER_LABELS: SpeakerLabels = SpeakerLabels::FirstName;
+    const DEFAULT_FAREWELL_KEYWORDS: &'static [&'static str] =
+        &["bye", "goodbye", "good bye", "farewell", "see you", "see ya"];
+
+    pub fn model_spec(&self) -> &'static ModelSpec {
+        CONFIG.model(&self.model)
+    }
 
-    pub fn cycle_model(&mut self) -> Model {
-        use Model::*;
-        self.model = match self.model {
-            Ada => Babbage,
-            Babbage => Curie,
-            Curie => Davinci,
-            Davinci => Ada,
+    // how many tokens of history fit in the prompt, leaving room for the completion and the summary
+    pub fn history_token_budget(&self) -> usize {
+        let summary_tokens = if self.summarize_history {
+            SUMMARY_MAX_TOKENS as usize
+        } else {
+            0
         };
-        self.model
+        let persona_tokens = self.persona.as_deref().map_or(0, tokens::estimate);
+        self.model_spec().max_context.saturating_sub(
+            self.max_tokens as usize + PROMPT_OVERHEAD_TOKENS + summary_tokens + persona_tokens,
+        )
     }
 
-    pub const SETTINGS_CYCLE_MODEL: &'static str = "settings_cycle_model";
-    pub const SETTINGS_EDIT_TEMPERATURE: &'static str = "settings_edit_temperature";
+    pub fn toggle_prompt_format(&mut self) -> PromptFormat {
+        use PromptFormat::*;
+        self.prompt_format = match self.prompt_format {
+            Flat => Chat,
+            Chat => Flat,
+        };
+        self.prompt_format
+    }
+
+    pub fn cycle_repetition_recovery(&mut self) -> RepetitionRecovery {
+        use RepetitionRecovery::*;
+        self.repetition_recovery = match self.repetition_recovery {
+            Off => RaiseTemperature,
+            RaiseTemperature => Penalize,
+            Penalize => DropBotTurns,
+            DropBotTurns => ClearHistory,
+            ClearHistory => Off,
+        };
+        self.repetition_recovery
+    }
+
+    pub fn cycle_reply_delay(&mut self) -> u64 {
+        // values that aren't presets (e.g. from an older version) go back to the first preset
+        self.reply_delay_secs = Self::REPLY_DELAY_PRESETS
+            .iter()
+            .copied()
+            .find(|&delay| delay > self.reply_delay_secs)
+            .unwrap_or(Self::REPLY_DELAY_PRESETS[0]);
+        self.reply_delay_secs
+    }
+
+    pub fn cycle_farewell_detection(&mut self) -> FarewellDetection {
+        use FarewellDetection::*;
+        self.farewell_detection = match self.farewell_detection {
+            Off => Keywords,
+            Keywords => Classifier,
+            Classifier => ClassifierOnly,
+            ClassifierOnly => Off,
+        };
+        self.farewell_detection
+    }
+
+    pub fn cycle_reply_policy(&mut self) -> ReplyPolicy {
+        use ReplyPolicy::*;
+        self.reply_policy = match self.reply_policy {
+            Always => Mentioned,
+            Mentioned => RepliedTo,
+            RepliedTo => Random,
+            Random => Always,
+        };
+        self.reply_policy
+    }
+
+    pub fn cycle_reply_probability(&mut self) -> f64 {
+        self.reply_probability = Self::REPLY_PROBABILITY_PRESETS
+            .iter()
+            .copied()
+            .find(|&p| p > self.reply_probability)
+            .unwrap_or(Self::REPLY_PROBABILITY_PRESETS[0]);
+        self.reply_probability
+    }
+
+    pub fn cycle_speaker_labels(&mut self) -> SpeakerLabels {
+        use SpeakerLabels::*;
+        self.speaker_labels = match self.speaker_labels {
+            FirstName => FullName,
+            FullName => Username,
+            Username => FirstName,
+        };
+        self.speaker_labels
+    }
+
+    // the name of the built-in persona if one is selected, "custom" otherwise
+    pub fn persona_name(&self) -> &str {
+        match &self.persona {
+            None => "none",
+            Some(persona) => PERSONAS
+                .iter()
+                .find(|(_, text)| text == persona)
+                .map_or("custom", |(name, _)| name),
+        }
+    }
+
+    pub const SETTINGS_PICK_MODEL: &'static str = "settings_pick_model";
     pub const SETTINGS_TOGGLE_TRAILING_SPACE: &'static str = "settings_toggle_trailing_space";
     pub const SETTINGS_EDIT_STOP_TOKENS: &'static str = "settings_edit_stop_tokens";
     pub const SETTINGS_EDIT_BOT_NAME: &'static str = "settings_edit_bot_name";
+    pub const SETTINGS_TOGGLE_PROMPT_FORMAT: &'static str = "settings_toggle_prompt_format";
+    pub const SETTINGS_TOGGLE_SUMMARIZE_HISTORY: &'static str = "settings_toggle_summarize_history";
+    pub const SETTINGS_CYCLE_REPETITION_RECOVERY: &'static str =
+        "settings_cycle_repetition_recovery";
+    pub const SETTINGS_CYCLE_REPLY_DELAY: &'static str = "settings_cycle_reply_delay";
+    pub const SETTINGS_CYCLE_FAREWELL_DETECTION: &'static str =
+        "settings_cycle_farewell_detection";
+    pub const SETTINGS_CYCLE_REPLY_POLICY: &'static str = "settings_cycle_reply_policy";
+    pub const SETTINGS_CYCLE_REPLY_PROBABILITY: &'static str = "settings_cycle_reply_probability";
+    pub const SETTINGS_CYCLE_SPEAKER_LABELS: &'static str = "settings_cycle_speaker_labels";
+    pub const SETTINGS_EDIT_PERSONA: &'static str = "settings_edit_persona";
     pub const SETTINGS_DONE: &'static str = "settings_done";
 
     pub fn get_message_text(&self) -> String {
-        "Editing settings".to_string()
+        format!(
+            "Editing settings\nhistory budget: {} tokens",
+            self.history_token_budget()
+        )
     }
 
     pub fn get_done_text(&self) -> String {
         format!(
-            "Done editing settings\n    model: {:?}\n    temperature: {:.1}\n    \
-            trailing space: {}\n    stop tokens: {}",
-            self.model,
+            "Done editing settings\n    model: {}\n    history budget: {} tokens\n    \
+            prompt format: {:?}\n    temperature: {:.1}\n    max tokens: {}\n    top p: {:.2}\n    \
+            frequency penalty: {:.1}\n    presence penalty: {:.1}\n    best of: {}\n    \
+            trailing space: {}\n    \
+            stop tokens: {}\n    summarize history: {}\n    repetition recovery: {:?}\n    reply delay: {}s\n    \
+            end on farewell: {:?}\n    reply policy: {:?}\n    reply probability: {:.0}%\n    \
+            speaker labels: {:?}\n    persona: {}",
+            self.model_spec().display_name,
+            self.history_token_budget(),
+            self.prompt_format,
             self.temperature,
+            self.max_tokens,
+            self.top_p,
+            self.frequency_penalty,
+            self.presence_penalty,
+            self.best_of,
             self.trailing_space_in_prompt,
             self.stop_tokens
                 .iter()
                 .map(|t| format!("{:?}", t))
                 .join(", "),
+            self.summarize_history,
+            self.repetition_recovery,
+            self.reply_delay_secs,
+            self.farewell_detection,
+            self.reply_policy,
+            self.reply_probability * 100.,
+            self.speaker_labels,
+            self.persona_name(),
         )
     }
 
     pub fn get_inline_keyboard_markup(&self) -> InlineKeyboardMarkup {
-        let button_text: [&[(_, _)]; 4] = [
+        // the chat endpoint has no equivalent of best of, the button is hidden for chat models
+        let best_of_row = [(BEST_OF.button_text(self), BEST_OF.open_data)];
+        let best_of_row: &[_] = match self.model_spec().endpoint {
+            Endpoint::Completion => &best_of_row,
+            Endpoint::Chat => &[],
+        };
+        let button_text: [&[(_, _)]; 11] = [
             &[
                 (
-                    format!("model: {:?}", self.model),
-                    Self::SETTINGS_CYCLE_MODEL,
-                ),
-                (
-                    format!("temperature: {:.1}", self.temperature),
-                    Self::SETTINGS_EDIT_TEMPERATURE,
+                    format!("model: {}", self.model_spec().display_name),
+                    Self::SETTINGS_PICK_MODEL,
                 ),
+                (TEMPERATURE.button_text(self), TEMPERATURE.open_data),
+            ],
+            &[
+                (MAX_TOKENS.button_text(self), MAX_TOKENS.open_data),
+                (TOP_P.button_text(self), TOP_P.open_data),
+            ],
+            &[
+                (FREQUENCY_PENALTY.button_text(self), FREQUENCY_PENALTY.open_data),
+                (PRESENCE_PENALTY.button_text(self), PRESENCE_PENALTY.open_data),
             ],
+            best_of_row,
             &[
                 (
                     format!("trailing space: {}", self.trailing_space_in_prompt),
@@ -83,11 +367,7 @@ impl Settings {
                         "stop tokens: {}",
                         self.stop_tokens
                             .iter()
-                            .map(|t| if t == "\n" {
-                                "\\n".to_string()
-                            } else {
-                                format!("\"{}\"", t)
-                            })
+                            .map(|t| format_stop_token(t))
                             .join(", ")
                     ),
                     Self::SETTINGS_EDIT_STOP_TOKENS,
@@ -98,17 +378,64 @@ impl Settings {
                     format!("bot name: {}", self.bot_name),
                     Self::SETTINGS_EDIT_BOT_NAME,
                 ),
+                (
+                    format!("prompt format: {:?}", self.prompt_format),
+                    Self::SETTINGS_TOGGLE_PROMPT_FORMAT,
+                ),
+            ],
+            &[
+                (
+                    format!("summarize history: {}", self.summarize_history),
+                    Self::SETTINGS_TOGGLE_SUMMARIZE_HISTORY,
+                ),
+                (
+                    format!("repetition recovery: {:?}", self.repetition_recovery),
+                    Self::SETTINGS_CYCLE_REPETITION_RECOVERY,
+                ),
+            ],
+            &[
+                (
+                    format!("reply delay: {}s", self.reply_delay_secs),
+                    Self::SETTINGS_CYCLE_REPLY_DELAY,
+                ),
+                (
+                    format!("end on farewell: {:?}", self.farewell_detection),
+                    Self::SETTINGS_CYCLE_FAREWELL_DETECTION,
+                ),
+            ],
+            &[
+                (
+                    format!("reply policy: {:?}", self.reply_policy),
+                    Self::SETTINGS_CYCLE_REPLY_POLICY,
+                ),
+                (
+                    format!("reply probability: {:.0}%", self.reply_probability * 100.),
+                    Self::SETTINGS_CYCLE_REPLY_PROBABILITY,
+                ),
+            ],
+            &[
+                (
+                    format!("speaker labels: {:?}", self.speaker_labels),
+                    Self::SETTINGS_CYCLE_SPEAKER_LABELS,
+                ),
+                (
+                    format!("persona: {}", self.persona_name()),
+                    Self::SETTINGS_EDIT_PERSONA,
+                ),
             ],
             &[("done".to_string(), Self::SETTINGS_DONE)],
         ];
-        let buttons = button_text.into_iter().map(|row| {
-            row.iter().map(|(text, data)| {
-                InlineKeyboardButton::new(
-                    text,
-                    InlineKeyboardButtonKind::CallbackData(data.to_string()),
-                )
-            })
-        });
+        let buttons = button_text
+            .into_iter()
+            .filter(|row| !row.is_empty())
+            .map(|row| {
+                row.iter().map(|(text, data)| {
+                    InlineKeyboardButton::new(
+                        text,
+                        InlineKeyboardButtonKind::CallbackData(data.to_string()),
+                    )
+                })
+            });
         InlineKeyboardMarkup::new(buttons)
     }
 }
@@ -116,14 +443,32 @@ impl Settings {
 impl Default for Settings {
     fn default() -> Self {
         Self {
-            bot_name: "You".to_string(),
-            model: Self::DEFAULT_MODEL,
+            bot_name: Self::DEFAULT_BOT_NAME.to_string(),
+            model: CONFIG.default_model.clone(),
+            prompt_format: Self::DEFAULT_PROMPT_FORMAT,
             temperature: Self::DEFAULT_TEMPERATURE,
+            max_tokens: Self::DEFAULT_MAX_TOKENS,
+            top_p: Self::DEFAULT_TOP_P,
+            frequency_penalty: Self::DEFAULT_FREQUENCY_PENALTY,
+            presence_penalty: Self::DEFAULT_PRESENCE_PENALTY,
+            best_of: Self::DEFAULT_BEST_OF,
             trailing_space_in_prompt: Self::DEFAULT_TRAILING_SPACE,
             stop_tokens: Self::DEFAULT_STOP_TOKENS
                 .iter()
                 .map(ToString::to_string)
                 .collect_vec(),
+            summarize_history: Self::DEFAULT_SUMMARIZE_HISTORY,
+            repetition_recovery: Self::DEFAULT_REPETITION_RECOVERY,
+            reply_delay_secs: Self::DEFAULT_REPLY_DELAY_SECS,
+            farewell_detection: Self::DEFAULT_FAREWELL_DETECTION,
+            reply_policy: Self::DEFAULT_REPLY_POLICY,
+            reply_probability: Self::DEFAULT_REPLY_PROBABILITY,
+            speaker_labels: Self::DEFAULT_SPEAKER_LABELS,
+            persona: None,
+            farewell_keywords: Self::DEFAULT_FAREWELL_KEYWORDS
+                .iter()
+                .map(ToString::to_string)
+                .collect_vec(),
         }
     }
 }
@@ -0,0 +1,102 @@
+use crate::conversation::settings::Settings;
+
+pub const NUMERIC_EDITOR_PREFIX: &str = "num_";
+
+// a numeric settings field edited with +/- buttons, fields are registered with
+// numeric_setting! and listed in NUMERIC_SETTINGS
+pub struct NumericSetting {
+    // identifies the setting in callback data
+    pub key: &'static str,
+    pub name: &'static str,
+    pub open_data: &'static str,
+    pub min: f64,
+    pub max: f64,
+    // offered both as increments and decrements, smallest first
+    pub steps: &'static [f64],
+    // decimal places shown, values are also rounded to this precision
+    pub precision: usize,
+    get: fn(&Settings) -> f64,
+    set: fn(&mut Settings, f64),
+}
+
+// numeric_setting!(field, name, min, max, [steps], precision), integer fields are converted to
+// and from f64, rounding takes care of the fractional part
+macro_rules! numeric_setting {
+    ($field:ident, $name:literal, $min:literal, $max:literal, $steps:expr, $precision:literal) => {
+        NumericSetting {
+            key: stringify!($field),
+            name: $name,
+            open_data: concat!("num_", stringify!($field), "_open"),
+            min: $min,
+            max: $max,
+            steps: &$steps,
+            precision: $precision,
+            get: |settings| settings.$field as f64,
+            set: |settings, value| settings.$field = value as _,
+        }
+    };
+}
+
+pub const TEMPERATURE: NumericSetting =
+    numeric_setting!(temperature, "temperature", 0., 2., [0.1, 0.2, 0.5], 1);
+pub const MAX_TOKENS: NumericSetting =
+    numeric_setting!(max_tokens, "max tokens", 1., 1000., [10., 20., 50.], 0);
+pub const TOP_P: NumericSetting = numeric_setting!(top_p, "top p", 0., 1., [0.05, 0.1, 0.2], 2);
+pub const FREQUENCY_PENALTY: NumericSetting = numeric_setting!(
+    frequency_penalty,
+    "frequency penalty",
+    -2.,
+    2.,
+    [0.1, 0.2, 0.5],
+    1
+);
+pub const PRESENCE_PENALTY: NumericSetting = numeric_setting!(
+    presence_penalty,
+    "presence penalty",
+    -2.,
+    2.,
+    [0.1, 0.2, 0.5],
+    1
+);
+pub const BEST_OF: NumericSetting = numeric_setting!(best_of, "best of", 1., 5., [1., 2.], 0);
+
+pub const NUMERIC_SETTINGS: &[&NumericSetting] = &[
+    &TEMPERATURE,
+    &MAX_TOKENS,
+    &TOP_P,
+    &FREQUENCY_PENALTY,
+    &PRESENCE_PENALTY,
+    &BEST_OF,
+];
+
+impl NumericSetting {
+    pub fn find(key: &str) -> Option<&'static Self> {
+        NUMERIC_SETTINGS.iter().copied().find(|s| s.key == key)
+    }
+
+    pub fn get(&self, settings: &Settings) -> f64 {
+        (self.get)(settings)
+    }
+
+    pub fn format(&self, value: f64) -> String {
+        format!("{:.*}", self.precision, value)
+    }
+
+    pub fn format_value(&self, settings: &Settings) -> String {
+        self.format(self.get(settings))
+    }
+
+    // e.g. "temperature: 0.8", used for the button in the settings menu
+    pub fn button_text(&self, settings: &Settings) -> String {
+        format!("{}: {}", self.name, self.format_value(settings))
+    }
+
+    // clamped to the range and rounded so that repeated steps don't accumulate float error
+    pub fn apply_delta(&self, settings: &mut Settings, delta: f64) -> f64 {
+        let factor = 10f64.powi(self.precision as i32);
+        let value = ((self.get(settings) + delta) * factor).round() / factor;
+        let value = value.clamp(self.min, self.max);
+        (self.set)(settings, value);
+        value
+    }
+}
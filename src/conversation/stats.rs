@@ -0,0 +1,119 @@
+use crate::completion::Prompt;
+use crate::conversation::tokens;
+use crate::UserId;
+use chrono::{DateTime, Duration, Utc};
+use itertools::Itertools;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize)]
+pub struct Stats {
+    // conversations saved before stats were tracked count from when they were restored
+    #[serde(default = "Utc::now")]
+    pub started_at: DateTime<Utc>,
+    // messages sent by each participant, not including the bot
+    #[serde(default, deserialize_with = "deserialize_message_counts")]
+    pub messages_by_participant: HashMap<UserId, u64>,
+    // estimated, includes the prompts of summaries and retries
+    #[serde(default)]
+    pub tokens_used: u64,
+    #[serde(default)]
+    pub replies: u64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started_at: Utc::now(),
+            messages_by_participant: HashMap::new(),
+            tokens_used: 0,
+            replies: 0,
+        }
+    }
+}
+
+// older snapshots counted messages by speaker label instead of user id, those counts can't be
+// attributed to anyone anymore and are dropped instead of failing to restore the conversation
+fn deserialize_message_counts<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<UserId, u64>, D::Error> {
+    let counts = HashMap::<String, u64>::deserialize(deserializer)?;
+    Ok(counts
+        .into_iter()
+        .filter_map(|(key, count)| Some((key.parse().ok()?, count)))
+        .collect())
+}
+
+fn prompt_tokens(prompt: &Prompt) -> usize {
+    match prompt {
+        Prompt::Text(text) => tokens::estimate(text),
+        Prompt::Chat(messages) => messages
+            .iter()
+            .map(|m| {
+                // the role and message delimiters take a few tokens of their own
+                tokens::estimate(&m.content) + m.name.as_deref().map_or(0, tokens::estimate) + 4
+            })
+            .sum(),
+    }
+}
+
+// e.g. "2d 3h 15m", seconds are only shown for conversations under a minute old
+fn format_duration(duration: Duration) -> String {
+    let (days, hours, minutes) = (
+        duration.num_days(),
+        duration.num_hours() % 24,
+        duration.num_minutes() % 60,
+    );
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", duration.num_seconds().max(0))
+    }
+}
+
+impl Stats {
+    pub fn record_message(&mut self, participant: UserId) {
+        *self.messages_by_participant.entry(participant).or_default() += 1;
+    }
+
+    pub fn record_usage(&mut self, prompt: &Prompt, completion: &str) {
+        self.tokens_used += (prompt_tokens(prompt) + tokens::estimate(completion)) as u64;
+    }
+
+    pub fn uptime_text(&self) -> String {
+        format!(
+            "since {} UTC ({})",
+            self.started_at.format("%Y-%m-%d %H:%M"),
+            format_duration(Utc::now() - self.started_at)
+        )
+    }
+
+    pub fn get_message_text(
+        &self,
+        label: impl Fn(UserId) -> String,
+        cost_per_token: f64,
+    ) -> String {
+        let participants = if self.messages_by_participant.is_empty() {
+            "    no messages yet".to_string()
+        } else {
+            self.messages_by_participant
+                .iter()
+                .map(|(&user, count)| (label(user), count))
+                .sorted_by(|(a, m), (b, n)| n.cmp(m).then(a.cmp(b)))
+                .map(|(name, count)| format!("    {}: {}", name, count))
+                .join("\n")
+        };
+        format!(
+            "Conversation running {}\nreplies: {}\ntokens used: ~{} (~${:.4})\nmessages:\n{}",
+            self.uptime_text(),
+            self.replies,
+            self.tokens_used,
+            self.tokens_used as f64 * cost_per_token,
+            participants,
+        )
+    }
+}
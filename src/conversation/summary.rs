@@ -0,0 +1,102 @@
+use crate::completion::{ChatMessage, CompletionBackend, CompletionRequest, Endpoint, Prompt, Role};
+use crate::conversation::{tokens, Conversation, PROMPT_OVERHEAD_TOKENS};
+use crate::result::Result;
+use itertools::Itertools;
+
+pub const SUMMARY_MAX_TOKENS: u64 = 150;
+const SUMMARY_TEMPERATURE: f64 = 0.3;
+
+impl Conversation {
+    // instructions for summarizing the first chunk_len evicted messages
+    fn summary_instructions(&self, chunk_len: usize) -> String {
+        let transcript = self.evicted[..chunk_len]
+            .iter()
+            .map(|(speaker, msg)| format!("{}: {}", self.speaker_name(speaker), msg))
+            .join("\n");
+
+        match &self.summary {
+            None => format!(
+                "Summarize the following chat in a few sentences, keep names and important facts.\n\n{}",
+                transcript
+            ),
+            Some(summary) => format!(
+                "Here is a summary of a chat so far:\n{}\n\nUpdate the summary with the following \
+                messages, keep it to a few sentences, keep names and important facts.\n\n{}",
+                summary, transcript
+            ),
+        }
+    }
+
+    // how many of the oldest evicted messages fit in a single summary request, at least one so
+    // that the summary always makes progress
+    fn summary_chunk_len(&self) -> usize {
+        let budget = self.settings.model_spec().max_context.saturating_sub(
+            SUMMARY_MAX_TOKENS as usize
+                + PROMPT_OVERHEAD_TOKENS
+                + tokens::estimate(&self.summary_instructions(0)),
+        );
+        let mut used = 0;
+        let fitting = self
+            .evicted
+            .iter()
+            .take_while(|entry| {
+                used += self.entry_tokens(entry);
+                used <= budget
+            })
+            .count();
+        fitting.max(1)
+    }
+
+    // fold the messages that fell out of the history window into the running summary, a chunk at
+    // a time so that the prompt fits the model's context, if a request fails the old summary is
+    // kept and the remaining messages are summarized with a later reply instead
+    pub(super) async fn update_summary(&mut self, backend: &dyn CompletionBackend) {
+        while !self.evicted.is_empty() {
+            let chunk_len = self.summary_chunk_len();
+            match self.summarize(chunk_len, backend).await {
+                Ok(summary) => {
+                    self.summary = Some(summary);
+                    self.evicted.drain(..chunk_len);
+                }
+                Err(e) => {
+                    println!(">> failed to update summary, keeping the old one: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn summarize(
+        &mut self,
+        chunk_len: usize,
+        backend: &dyn CompletionBackend,
+    ) -> Result<String> {
+        let instructions = self.summary_instructions(chunk_len);
+        let prompt = match self.settings.model_spec().endpoint {
+            Endpoint::Completion => Prompt::Text(format!("{}\n\nSummary:", instructions)),
+            Endpoint::Chat => Prompt::Chat(vec![ChatMessage {
+                role: Role::User,
+                name: None,
+                content: instructions,
+            }]),
+        };
+        let request = CompletionRequest {
+            prompt,
+            engine: self.settings.model_spec().id.clone(),
+            max_tokens: SUMMARY_MAX_TOKENS,
+            temperature: SUMMARY_TEMPERATURE,
+            top_p: 1.,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+            best_of: 1,
+            stop: vec![],
+        };
+
+        println!(">> summarizing {} message(s)", chunk_len);
+        let prompt = request.prompt.clone();
+        let summary = backend.complete(request).await?.trim().to_string();
+        self.stats.record_usage(&prompt, &summary);
+        println!(">> new summary: {:?}", summary);
+        Ok(summary)
+    }
+}
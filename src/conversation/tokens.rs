@@ -0,0 +1,21 @@
+// rough local estimate of how many tokens the api will count for a piece of text, gpt tokenizers
+// average about four characters per token for english words and usually give punctuation
+// and other symbols a token of their own
+pub fn estimate(text: &str) -> usize {
+    let mut tokens = 0;
+    let mut word_len = 0;
+
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            word_len += 1;
+        } else {
+            tokens += (word_len + 3) / 4;
+            word_len = 0;
+            if !c.is_whitespace() {
+                tokens += 1;
+            }
+        }
+    }
+
+    tokens + (word_len + 3) / 4
+}
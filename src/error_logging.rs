@@ -1,46 +1,236 @@
 use crate::result::{Error, Result};
-use chrono::Utc;
-use std::fs::{File, OpenOptions};
-use std::io;
-use std::io::{BufWriter, Write};
+use crate::ChatId;
+use chrono::{DateTime, NaiveDate, Utc};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
-const LOG_FILE_PATH: &str = "error_log.txt";
+pub mod cli;
 
-pub struct ErrorLogger(BufWriter<File>);
+const LOG_DIR: &str = "logs";
+const LOG_FILE_PREFIX: &str = "error_log";
+const LOG_FILE_EXTENSION: &str = "jsonl";
+// the log is rotated when it grows past this size and on the first record of a new day
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+const MAX_ROTATED_LOGS: usize = 30;
 
-fn generate_log_line(error: &Error) -> String {
-    let utc = Utc::now();
-    format!("[UTC {:?}] {:?}\n", utc, error)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+// where an error happened, filled in by the handler that got it
+#[derive(Clone, Debug)]
+pub struct ErrorContext {
+    pub handler: &'static str,
+    pub chat_id: Option<ChatId>,
+    // message id for messages, query id for callback queries, teloxide doesn't pass the
+    // telegram update id on to the handlers
+    pub update_id: Option<String>,
+}
+
+// one line of the log
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorRecord {
+    pub timestamp: DateTime<Utc>,
+    pub severity: Severity,
+    // e.g. "request" or "app::MessageTooOld"
+    pub kind: String,
+    pub handler: String,
+    pub chat_id: Option<ChatId>,
+    pub update_id: Option<String>,
+    pub details: String,
+}
+
+// the variant name of an error, without its fields
+fn variant_name(error: &impl Debug) -> String {
+    let debug = format!("{:?}", error);
+    debug
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+impl Error {
+    fn kind(&self) -> String {
+        use Error::*;
+        match self {
+            Request(_) => "request".to_string(),
+            Io(_) => "io".to_string(),
+            Completion(e) => format!("completion::{}", variant_name(e)),
+            Serialization(_) => "serialization".to_string(),
+            App(e) => format!("app::{}", variant_name(e)),
+        }
+    }
+
+    fn severity(&self) -> Severity {
+        use crate::result::AppError::*;
+        match self {
+            Error::App(MessageTooOld)
+            | Error::App(QuotaExceeded(_))
+            | Error::App(NotAuthorized(..)) => Severity::Info,
+            Error::App(_) => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+fn log_file_path(dir: &Path) -> PathBuf {
+    dir.join(format!("{}.{}", LOG_FILE_PREFIX, LOG_FILE_EXTENSION))
+}
+
+// the current log and all rotated ones, oldest first, rotated logs carry a timestamp in their
+// name so that they sort before the current one
+pub fn log_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if name.starts_with(LOG_FILE_PREFIX) && name.ends_with(LOG_FILE_EXTENSION) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// records are written to stderr instead if the log file can't be opened or written to
+pub struct ErrorLogger {
+    dir: PathBuf,
+    file: Option<BufWriter<File>>,
+    size: u64,
+    opened_on: NaiveDate,
 }
 
 impl ErrorLogger {
     pub fn new() -> Self {
+        let mut logger = Self {
+            dir: PathBuf::from(LOG_DIR),
+            file: None,
+            size: 0,
+            opened_on: Utc::now().naive_utc().date(),
+        };
+        match logger.open() {
+            Ok(()) => println!("opened error file"),
+            Err(e) => eprintln!("failed to open error log, logging to stderr: {:?}", e),
+        }
+        logger.write_record(&ErrorRecord {
+            timestamp: Utc::now(),
+            severity: Severity::Info,
+            kind: "restart".to_string(),
+            handler: "main".to_string(),
+            chat_id: None,
+            update_id: None,
+            details: String::new(),
+        });
+        logger
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
         let file = OpenOptions::new()
             .create(true)
             .append(true)
-            .open(LOG_FILE_PATH)
-            .expect("failed to open or create error log file");
+            .open(log_file_path(&self.dir))?;
+        self.size = file.metadata()?.len();
+        self.opened_on = Utc::now().naive_utc().date();
+        self.file = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    fn needs_rotation(&self) -> bool {
+        self.size >= MAX_LOG_SIZE || Utc::now().naive_utc().date() != self.opened_on
+    }
 
-        let mut bw = BufWriter::new(file);
-        let line = format!("\n[UTC {:?}] RESTART\n", Utc::now());
-        bw.write_all(line.as_bytes())
-            .expect("failed to write to error log");
+    // move the current log aside and start a new one, the oldest logs are deleted
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+
+        let path = log_file_path(&self.dir);
+        if path.exists() {
+            let rotated = self.dir.join(format!(
+                "{}.{}.{}",
+                LOG_FILE_PREFIX,
+                Utc::now().format("%Y-%m-%dT%H-%M-%S"),
+                LOG_FILE_EXTENSION
+            ));
+            fs::rename(&path, rotated)?;
+        }
+
+        let rotated = log_files(&self.dir)?
+            .into_iter()
+            .filter(|p| *p != path)
+            .collect_vec();
+        for old in &rotated[..rotated.len().saturating_sub(MAX_ROTATED_LOGS)] {
+            fs::remove_file(old)?;
+        }
 
-        println!("opened error file");
-        Self(bw)
+        self.open()
     }
 
-    pub fn maybe_log<T>(&mut self, result: &Result<T>) {
-        if let Err(e) = result {
-            let line = generate_log_line(e);
-            self.0
+    fn write_record(&mut self, record: &ErrorRecord) {
+        let line = match serde_json::to_string(record) {
+            Ok(json) => json + "\n",
+            Err(e) => {
+                eprintln!("failed to serialize error record: {:?}\n{:?}", e, record);
+                return;
+            }
+        };
+
+        if self.needs_rotation() {
+            println!("rotating error log");
+            if let Err(e) = self.rotate() {
+                eprintln!("failed to rotate error log: {:?}", e);
+            }
+        }
+        // retry opening in case the problem was temporary (e.g. a full disk)
+        if self.file.is_none() {
+            let _ = self.open();
+        }
+
+        let result = match &mut self.file {
+            Some(file) => file
                 .write_all(line.as_bytes())
-                .expect("failed to write to error log");
+                .and_then(|()| file.flush()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no error log file")),
+        };
+        match result {
+            Ok(()) => self.size += line.len() as u64,
+            Err(e) => {
+                self.file = None;
+                eprintln!("failed to write to error log: {:?}\n{}", e, line.trim_end());
+            }
+        }
+    }
+
+    pub fn maybe_log<T>(&mut self, result: &Result<T>, context: ErrorContext) {
+        if let Err(e) = result {
+            self.write_record(&ErrorRecord {
+                timestamp: Utc::now(),
+                severity: e.severity(),
+                kind: e.kind(),
+                handler: context.handler.to_string(),
+                chat_id: context.chat_id,
+                update_id: context.update_id,
+                details: format!("{:?}", e),
+            });
         }
     }
 
     pub fn flush(&mut self) -> io::Result<()> {
         println!("flushing error file");
-        self.0.flush()
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
     }
 }
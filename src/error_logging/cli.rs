@@ -0,0 +1,168 @@
+use crate::error_logging::{log_files, ErrorRecord, Severity, LOG_DIR};
+use crate::ChatId;
+use chrono::NaiveDate;
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::hash::Hash;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "usage: conversation_bot errors [--summary] [--since YYYY-MM-DD] \
+    [--until YYYY-MM-DD] [--chat ID] [--severity info|warning|error] [--handler NAME] \
+    [--kind PREFIX]";
+
+#[derive(Default)]
+struct Filter {
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+    chat_id: Option<ChatId>,
+    // records at least this severe
+    severity: Option<Severity>,
+    handler: Option<String>,
+    kind: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, record: &ErrorRecord) -> bool {
+        let date = record.timestamp.naive_utc().date();
+        self.since.map_or(true, |since| date >= since)
+            && self.until.map_or(true, |until| date <= until)
+            && self
+                .chat_id
+                .map_or(true, |chat_id| record.chat_id == Some(chat_id))
+            && self
+                .severity
+                .map_or(true, |severity| record.severity >= severity)
+            && self
+                .handler
+                .as_ref()
+                .map_or(true, |handler| record.handler == *handler)
+            && self
+                .kind
+                .as_ref()
+                .map_or(true, |kind| record.kind.starts_with(kind.as_str()))
+    }
+}
+
+fn usage_error(message: impl Display) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2)
+}
+
+fn parse_value<T>(flag: &str, value: Option<String>, parse: impl FnOnce(&str) -> Option<T>) -> T {
+    let value = value.unwrap_or_else(|| usage_error(format!("{} needs a value", flag)));
+    parse(&value).unwrap_or_else(|| usage_error(format!("invalid value for {}: {}", flag, value)))
+}
+
+fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+fn parse_severity(severity: &str) -> Option<Severity> {
+    serde_json::from_value(serde_json::Value::String(severity.to_lowercase())).ok()
+}
+
+fn print_counts<K: Display + Eq + Hash>(title: &str, counts: HashMap<K, usize>) {
+    println!("{}:", title);
+    for (key, count) in counts.into_iter().sorted_by(|(_, a), (_, b)| b.cmp(a)) {
+        println!("    {}: {}", key, count);
+    }
+}
+
+fn print_summary(records: &[ErrorRecord]) {
+    let (first, last) = match (records.first(), records.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            println!("no matching records");
+            return;
+        }
+    };
+    println!(
+        "{} record(s) from {} to {}",
+        records.len(),
+        first.timestamp.format("%Y-%m-%d %H:%M:%S"),
+        last.timestamp.format("%Y-%m-%d %H:%M:%S"),
+    );
+    print_counts(
+        "by severity",
+        records.iter().map(|r| format!("{:?}", r.severity)).counts(),
+    );
+    print_counts("by kind", records.iter().map(|r| &r.kind).counts());
+    print_counts("by handler", records.iter().map(|r| &r.handler).counts());
+    print_counts(
+        "by chat",
+        records
+            .iter()
+            .map(|r| r.chat_id.map_or("none".to_string(), |c| c.to_string()))
+            .counts(),
+    );
+}
+
+// `conversation_bot errors ...`, prints the matching records as json lines or a summary of them
+pub fn run(args: impl IntoIterator<Item = String>) {
+    let mut filter = Filter::default();
+    let mut summary = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--summary" => summary = true,
+            "--since" => filter.since = Some(parse_value(&arg, args.next(), parse_date)),
+            "--until" => filter.until = Some(parse_value(&arg, args.next(), parse_date)),
+            "--chat" => filter.chat_id = Some(parse_value(&arg, args.next(), |c| c.parse().ok())),
+            "--severity" => filter.severity = Some(parse_value(&arg, args.next(), parse_severity)),
+            "--handler" => {
+                filter.handler = Some(parse_value(&arg, args.next(), |h| Some(h.to_string())))
+            }
+            "--kind" => filter.kind = Some(parse_value(&arg, args.next(), |k| Some(k.to_string()))),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            arg => usage_error(format!("unexpected argument: {}", arg)),
+        }
+    }
+
+    let files = match log_files(Path::new(LOG_DIR)) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("failed to read the log directory {:?}: {:?}", LOG_DIR, e);
+            process::exit(1)
+        }
+    };
+
+    let mut records = vec![];
+    let mut malformed = 0;
+    for path in files {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("failed to read {:?}: {:?}", path, e);
+                continue;
+            }
+        };
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str::<ErrorRecord>(line) {
+                Ok(record) if filter.matches(&record) => {
+                    if summary {
+                        records.push(record);
+                    } else {
+                        println!("{}", line);
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => malformed += 1,
+            }
+        }
+    }
+
+    if summary {
+        records.sort_by_key(|r| r.timestamp);
+        print_summary(&records);
+    }
+    if malformed > 0 {
+        eprintln!("skipped {} malformed line(s)", malformed);
+    }
+}
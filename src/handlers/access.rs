@@ -0,0 +1,67 @@
+use crate::result::{AppError, Result};
+use crate::{ChatId, UserId, CONFIG};
+use futures::lock::Mutex;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+use teloxide::types::{Chat, MessageKind, User};
+
+// admin status is cached for a while so that tapping through the settings dialog doesn't cost
+// a getChatMember request per button
+const ADMIN_CACHE_DURATION: Duration = Duration::from_secs(300);
+
+lazy_static! {
+    static ref ADMIN_CACHE: Mutex<HashMap<(ChatId, UserId), (bool, Instant)>> = Default::default();
+}
+
+async fn is_chat_admin(bot: &Bot, chat: ChatId, user: UserId) -> Result<bool> {
+    if let Some(&(is_admin, checked)) = ADMIN_CACHE.lock().await.get(&(chat, user)) {
+        if checked.elapsed() < ADMIN_CACHE_DURATION {
+            return Ok(is_admin);
+        }
+    }
+
+    let member = bot.get_chat_member(chat, user).send().await?;
+    let is_admin = member.kind.is_privileged();
+    ADMIN_CACHE
+        .lock()
+        .await
+        .insert((chat, user), (is_admin, Instant::now()));
+    Ok(is_admin)
+}
+
+// owners from the config can do anything anywhere, otherwise restricted actions are open to
+// everyone unless the config restricts them to chat admins, private chats aren't restricted
+pub async fn is_authorized(bot: &Bot, chat: &Chat, user: &User) -> Result<bool> {
+    if CONFIG.access.owners.contains(&user.id) {
+        return Ok(true);
+    }
+    if !CONFIG.access.restrict_to_admins || chat.is_private() {
+        return Ok(true);
+    }
+    is_chat_admin(bot, chat.id, user.id).await
+}
+
+// messages from anonymous admins are sent on behalf of the chat itself
+pub async fn is_message_authorized(bot: &Bot, message: &Message) -> Result<bool> {
+    if let MessageKind::Common(common) = &message.kind {
+        if matches!(&common.sender_chat, Some(sender) if sender.id == message.chat.id) {
+            return Ok(true);
+        }
+    }
+    match message.from() {
+        Some(user) => is_authorized(bot, &message.chat, user).await,
+        None => Ok(false),
+    }
+}
+
+pub async fn ensure_message_authorized(bot: &Bot, message: &Message) -> Result {
+    if !is_message_authorized(bot, message).await? {
+        Err(AppError::NotAuthorized(
+            message.chat.id,
+            message.from().map(|u| u.id),
+        ))?
+    }
+    Ok(())
+}
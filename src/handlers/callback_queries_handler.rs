@@ -1,65 +1,266 @@
-use crate::conversation::settings::Settings;
+use crate::conversation::settings::{format_stop_token, parse_stop_token, Settings, PERSONAS};
+use crate::conversation::Conversation;
+use crate::handlers::access::is_authorized;
+use crate::error_logging::ErrorContext;
+use crate::handlers::save_conversation;
+use crate::handlers::error_replies::report_callback_error;
 use crate::result::{Error, Result};
-use crate::{AppError, ChatId, MessageId, CONVERSATIONS, ERROR_LOGGER};
+use crate::{AppError, ChatId, MessageId, CONFIG, CONVERSATIONS, ERROR_LOGGER};
 use async_trait::async_trait;
 use itertools::Itertools;
 use teloxide::prelude::*;
 use teloxide::types::{InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup};
 use tokio_stream::wrappers::UnboundedReceiverStream;
+use crate::conversation::settings::numeric::NUMERIC_EDITOR_PREFIX;
 use crate::handlers::messages_handler::{SPECIAL_HANDLERS, SpecialHandler};
+use crate::handlers::numeric_editor::handle_numeric_editor_callback_query;
 
-const TEMP_SUB_05: &str = "temp_sub_0.5";
-const TEMP_SUB_02: &str = "temp_sub_0.2";
-const TEMP_SUB_01: &str = "temp_sub_0.1";
-const TEMP_ADD_01: &str = "temp_add_0.1";
-const TEMP_ADD_02: &str = "temp_add_0.2";
-const TEMP_ADD_05: &str = "temp_add_0.5";
-const TEMP_BACK: &str = "temp_back";
-
-fn get_temperature_editor_markup() -> InlineKeyboardMarkup {
-    const BUTTON_ROW_TEXT: [(&str, &str); 6] = [
-        ("-0.5", TEMP_SUB_05),
-        ("-0.2", TEMP_SUB_02),
-        ("-0.1", TEMP_SUB_01),
-        ("+0.1", TEMP_ADD_01),
-        ("+0.2", TEMP_ADD_02),
-        ("+0.5", TEMP_ADD_05),
-    ];
-    let button_row = BUTTON_ROW_TEXT
-        .into_iter()
-        .map(|(text, data)| {
-            InlineKeyboardButton::new(
-                text,
-                InlineKeyboardButtonKind::CallbackData(data.to_string()),
-            )
-        })
-        .collect_vec();
+const MODEL_PICK_PREFIX: &str = "model_pick_";
+const MODEL_BACK: &str = "model_back";
+
+// one button per model in the config, the current one is marked
+fn get_model_picker_markup(settings: &Settings) -> InlineKeyboardMarkup {
+    let current = settings.model_spec();
+    let model_rows = CONFIG.models.iter().map(|model| {
+        let text = if model.id == current.id {
+            format!("{} (current)", model.display_name)
+        } else {
+            model.display_name.clone()
+        };
+        vec![InlineKeyboardButton::new(
+            text,
+            InlineKeyboardButtonKind::CallbackData(format!("{}{}", MODEL_PICK_PREFIX, model.id)),
+        )]
+    });
     let back_button = InlineKeyboardButton::new(
         "back",
-        InlineKeyboardButtonKind::CallbackData(TEMP_BACK.to_string()),
+        InlineKeyboardButtonKind::CallbackData(MODEL_BACK.to_string()),
     );
-    InlineKeyboardMarkup::new([button_row, vec![back_button]])
+    InlineKeyboardMarkup::new(model_rows.chain([vec![back_button]]))
 }
 
-fn get_temperature_editor_dialog_text(current_value: f64) -> String {
-    format!("Editing temperature\ncurrent value: {:.1}", current_value)
+fn get_model_picker_dialog_text(settings: &Settings) -> String {
+    let model = settings.model_spec();
+    format!(
+        "Picking model\ncurrent model: {}\ncontext size: {} tokens",
+        model.display_name, model.max_context
+    )
 }
 
-async fn handle_temperature_editor_callback_query(
+async fn handle_model_picker_callback_query(
     cx: &UpdateWithCx<&Bot, CallbackQuery>,
     data: &str,
     chat_id: ChatId,
     message_id: MessageId,
     settings: &mut Settings,
 ) -> Result {
-    let delta = match data {
-        TEMP_SUB_05 => -0.5,
-        TEMP_SUB_02 => -0.2,
-        TEMP_SUB_01 => -0.1,
-        TEMP_ADD_01 => 0.1,
-        TEMP_ADD_02 => 0.2,
-        TEMP_ADD_05 => 0.5,
-        TEMP_BACK => {
+    if data == MODEL_BACK {
+        cx.requester
+            .edit_message_text(chat_id, message_id, settings.get_message_text())
+            .reply_markup(settings.get_inline_keyboard_markup())
+            .send()
+            .await?;
+
+        return Ok(());
+    }
+
+    let model = data
+        .strip_prefix(MODEL_PICK_PREFIX)
+        .and_then(|id| CONFIG.models.iter().find(|m| m.id == id))
+        .ok_or_else(|| AppError::UnexpectedCallbackQueryData(data.to_string()))?;
+    settings.model = model.id.clone();
+
+    cx.requester
+        .edit_message_text(chat_id, message_id, get_model_picker_dialog_text(settings))
+        .reply_markup(get_model_picker_markup(settings))
+        .send()
+        .await?;
+
+    println!("set model to {:?}", settings.model);
+
+    cx.requester
+        .answer_callback_query(cx.update.id.clone())
+        .text(format!("Set model to: {}", model.display_name))
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+const STOP_REMOVE_PREFIX: &str = "stop_remove_";
+const STOP_TOGGLE_NEWLINE: &str = "stop_toggle_newline";
+const STOP_TOGGLE_PUNCTUATION: &str = "stop_toggle_punctuation";
+const STOP_ADD_CUSTOM: &str = "stop_add_custom";
+const STOP_BACK: &str = "stop_back";
+
+const NEWLINE_PRESET: &[&str] = &["\n"];
+const PUNCTUATION_PRESET: &[&str] = &[".", "!", "?"];
+
+fn preset_enabled(settings: &Settings, preset: &[&str]) -> bool {
+    preset
+        .iter()
+        .all(|token| settings.stop_tokens.iter().any(|t| t == token))
+}
+
+// enable the preset if any of its tokens is missing, otherwise remove all of them
+fn toggle_preset(settings: &mut Settings, preset: &[&str]) -> bool {
+    if preset_enabled(settings, preset) {
+        settings
+            .stop_tokens
+            .retain(|t| !preset.contains(&t.as_str()));
+        false
+    } else {
+        for token in preset {
+            if !settings.stop_tokens.iter().any(|t| t == token) {
+                settings.stop_tokens.push(token.to_string());
+            }
+        }
+        true
+    }
+}
+
+fn get_stop_tokens_editor_markup(settings: &Settings) -> InlineKeyboardMarkup {
+    let on_off = |enabled| if enabled { "on" } else { "off" };
+
+    // one removable button per token, a few per row
+    let token_rows = settings
+        .stop_tokens
+        .iter()
+        .enumerate()
+        .map(|(i, token)| {
+            InlineKeyboardButton::new(
+                format!("remove {}", format_stop_token(token)),
+                InlineKeyboardButtonKind::CallbackData(format!("{}{}", STOP_REMOVE_PREFIX, i)),
+            )
+        })
+        .chunks(3)
+        .into_iter()
+        .map(|chunk| chunk.collect_vec())
+        .collect_vec();
+
+    let button_text = [
+        [
+            (
+                format!(
+                    "newline: {}",
+                    on_off(preset_enabled(settings, NEWLINE_PRESET))
+                ),
+                STOP_TOGGLE_NEWLINE,
+            ),
+            (
+                format!(
+                    "punctuation: {}",
+                    on_off(preset_enabled(settings, PUNCTUATION_PRESET))
+                ),
+                STOP_TOGGLE_PUNCTUATION,
+            ),
+        ],
+        [
+            ("add custom".to_string(), STOP_ADD_CUSTOM),
+            ("back".to_string(), STOP_BACK),
+        ],
+    ];
+    let other_rows = button_text.into_iter().map(|row| {
+        row.into_iter()
+            .map(|(text, data)| {
+                InlineKeyboardButton::new(
+                    text,
+                    InlineKeyboardButtonKind::CallbackData(data.to_string()),
+                )
+            })
+            .collect_vec()
+    });
+
+    InlineKeyboardMarkup::new(token_rows.into_iter().chain(other_rows))
+}
+
+fn get_stop_tokens_editor_dialog_text(settings: &Settings) -> String {
+    if settings.stop_tokens.is_empty() {
+        "Editing stop tokens\nno stop tokens set".to_string()
+    } else {
+        format!(
+            "Editing stop tokens\ncurrent tokens: {}",
+            settings
+                .stop_tokens
+                .iter()
+                .map(|t| format_stop_token(t))
+                .join(", ")
+        )
+    }
+}
+
+struct AddStopTokenHandler(ChatId);
+
+#[async_trait]
+impl SpecialHandler for AddStopTokenHandler {
+    async fn handle_message(&self, cx: UpdateWithCx<&Bot, Message>) -> Result<bool> {
+        match cx.update.text().map(parse_stop_token) {
+            Some(token) if !token.is_empty() => {
+                println!("adding stop token {:?}", token);
+                let handle = CONVERSATIONS
+                    .lock()
+                    .await
+                    .get(self.0)
+                    .ok_or(Error::App(AppError::NoConversationRunning(self.0)))?;
+                let mut conversation = handle.lock().await;
+                let settings = &mut conversation.settings;
+                let formatted = format_stop_token(&token);
+                if !settings.stop_tokens.contains(&token) {
+                    settings.stop_tokens.push(token);
+                }
+                save_conversation(self.0, &handle, &conversation).await?;
+                cx.answer(format!("Added stop token {}", formatted))
+                    .send()
+                    .await?;
+                Ok(true)
+            }
+            _ => {
+                cx.answer("Stop token must be non-empty text").send().await?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+async fn handle_stop_tokens_editor_callback_query(
+    cx: &UpdateWithCx<&Bot, CallbackQuery>,
+    data: &str,
+    chat_id: ChatId,
+    message_id: MessageId,
+    conversation: &mut Conversation,
+) -> Result {
+    let settings = &mut conversation.settings;
+    let toast = match data {
+        STOP_TOGGLE_NEWLINE => {
+            let enabled = toggle_preset(settings, NEWLINE_PRESET);
+            format!("Newline stop token: {}", if enabled { "on" } else { "off" })
+        }
+        STOP_TOGGLE_PUNCTUATION => {
+            let enabled = toggle_preset(settings, PUNCTUATION_PRESET);
+            format!("Punctuation stop tokens: {}", if enabled { "on" } else { "off" })
+        }
+        STOP_ADD_CUSTOM => {
+            conversation
+                .replace_settings_dialog(
+                    "Enter a new stop token (use \\n for a newline):",
+                    cx.requester,
+                )
+                .await?;
+
+            SPECIAL_HANDLERS
+                .lock()
+                .await
+                .insert(chat_id, Box::new(AddStopTokenHandler(chat_id)));
+
+            cx.requester
+                .answer_callback_query(cx.update.id.clone())
+                .text("Opened stop token dialog")
+                .send()
+                .await?;
+
+            return Ok(());
+        }
+        STOP_BACK => {
             cx.requester
                 .edit_message_text(chat_id, message_id, settings.get_message_text())
                 .reply_markup(settings.get_inline_keyboard_markup())
@@ -69,35 +270,184 @@ async fn handle_temperature_editor_callback_query(
             return Ok(());
         }
         data => {
-            return Err(Error::App(AppError::UnexpectedCallbackQueryData(
-                data.to_string(),
-            )))
+            let index = data
+                .strip_prefix(STOP_REMOVE_PREFIX)
+                .and_then(|i| i.parse::<usize>().ok())
+                .filter(|&i| i < settings.stop_tokens.len())
+                .ok_or_else(|| AppError::UnexpectedCallbackQueryData(data.to_string()))?;
+            let removed = settings.stop_tokens.remove(index);
+            format!("Removed stop token {}", format_stop_token(&removed))
         }
     };
-    let mut new_temperature = settings.temperature + delta;
-    if new_temperature < 0. {
-        new_temperature = 0.
-    };
-    if new_temperature > 2. {
-        new_temperature = 2.
-    };
-    settings.temperature = new_temperature;
 
     cx.requester
         .edit_message_text(
             chat_id,
             message_id,
-            get_temperature_editor_dialog_text(settings.temperature),
+            get_stop_tokens_editor_dialog_text(settings),
+        )
+        .reply_markup(get_stop_tokens_editor_markup(settings))
+        .send()
+        .await?;
+
+    println!("set stop tokens to {:?}", settings.stop_tokens);
+
+    cx.requester
+        .answer_callback_query(cx.update.id.clone())
+        .text(toast)
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+const PERSONA_PICK_PREFIX: &str = "persona_pick_";
+const PERSONA_CUSTOM: &str = "persona_custom";
+const PERSONA_CLEAR: &str = "persona_clear";
+const PERSONA_BACK: &str = "persona_back";
+
+// one button per built-in persona, the current one is marked
+fn get_persona_editor_markup(settings: &Settings) -> InlineKeyboardMarkup {
+    let current = settings.persona_name();
+    let persona_rows = PERSONAS
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| {
+            let text = if *name == current {
+                format!("{} (current)", name)
+            } else {
+                name.to_string()
+            };
+            InlineKeyboardButton::new(
+                text,
+                InlineKeyboardButtonKind::CallbackData(format!("{}{}", PERSONA_PICK_PREFIX, i)),
+            )
+        })
+        .chunks(2)
+        .into_iter()
+        .map(|chunk| chunk.collect_vec())
+        .collect_vec();
+
+    let other_row = [
+        ("write custom", PERSONA_CUSTOM),
+        ("clear", PERSONA_CLEAR),
+        ("back", PERSONA_BACK),
+    ]
+    .into_iter()
+    .map(|(text, data)| {
+        InlineKeyboardButton::new(
+            text,
+            InlineKeyboardButtonKind::CallbackData(data.to_string()),
         )
-        .reply_markup(get_temperature_editor_markup())
+    })
+    .collect_vec();
+
+    InlineKeyboardMarkup::new(persona_rows.into_iter().chain([other_row]))
+}
+
+fn get_persona_editor_dialog_text(settings: &Settings) -> String {
+    match &settings.persona {
+        None => "Editing persona\nno persona set".to_string(),
+        Some(persona) => format!(
+            "Editing persona\ncurrent persona ({}): {}",
+            settings.persona_name(),
+            persona
+        ),
+    }
+}
+
+struct EditPersonaHandler(ChatId);
+
+#[async_trait]
+impl SpecialHandler for EditPersonaHandler {
+    async fn handle_message(&self, cx: UpdateWithCx<&Bot, Message>) -> Result<bool> {
+        match cx.update.text().map(str::trim) {
+            Some(persona) if !persona.is_empty() => {
+                println!("setting persona to {:?}", persona);
+                let handle = CONVERSATIONS
+                    .lock()
+                    .await
+                    .get(self.0)
+                    .ok_or(Error::App(AppError::NoConversationRunning(self.0)))?;
+                let mut conversation = handle.lock().await;
+                conversation.settings.persona = Some(persona.to_string());
+                save_conversation(self.0, &handle, &conversation).await?;
+                cx.answer("Set a custom persona").send().await?;
+                Ok(true)
+            }
+            _ => {
+                cx.answer("Persona must be non-empty text").send().await?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+async fn handle_persona_editor_callback_query(
+    cx: &UpdateWithCx<&Bot, CallbackQuery>,
+    data: &str,
+    chat_id: ChatId,
+    message_id: MessageId,
+    conversation: &mut Conversation,
+) -> Result {
+    let settings = &mut conversation.settings;
+    let toast = match data {
+        PERSONA_CUSTOM => {
+            conversation
+                .replace_settings_dialog(
+                    "Describe who the bot is and how it should behave:",
+                    cx.requester,
+                )
+                .await?;
+
+            SPECIAL_HANDLERS
+                .lock()
+                .await
+                .insert(chat_id, Box::new(EditPersonaHandler(chat_id)));
+
+            cx.requester
+                .answer_callback_query(cx.update.id.clone())
+                .text("Opened persona dialog")
+                .send()
+                .await?;
+
+            return Ok(());
+        }
+        PERSONA_CLEAR => {
+            settings.persona = None;
+            "Cleared persona".to_string()
+        }
+        PERSONA_BACK => {
+            cx.requester
+                .edit_message_text(chat_id, message_id, settings.get_message_text())
+                .reply_markup(settings.get_inline_keyboard_markup())
+                .send()
+                .await?;
+
+            return Ok(());
+        }
+        data => {
+            let (name, persona) = data
+                .strip_prefix(PERSONA_PICK_PREFIX)
+                .and_then(|i| i.parse::<usize>().ok())
+                .and_then(|i| PERSONAS.get(i))
+                .ok_or_else(|| AppError::UnexpectedCallbackQueryData(data.to_string()))?;
+            settings.persona = Some(persona.to_string());
+            format!("Set persona to: {}", name)
+        }
+    };
+
+    cx.requester
+        .edit_message_text(chat_id, message_id, get_persona_editor_dialog_text(settings))
+        .reply_markup(get_persona_editor_markup(settings))
         .send()
         .await?;
 
-    println!("set temperature to {:?}", new_temperature);
+    println!("set persona to {:?}", settings.persona);
 
     cx.requester
         .answer_callback_query(cx.update.id.clone())
-        .text(format!("Set temperature to: {:.1}", new_temperature))
+        .text(toast)
         .send()
         .await?;
 
@@ -113,10 +463,19 @@ async fn handle_callback_query(cx: UpdateWithCx<&Bot, CallbackQuery>) -> Result
 
     let chat_id = message.chat_id();
 
-    let mut conversations = CONVERSATIONS.lock().await;
-    let conversation = conversations
-        .get_mut(chat_id)
+    // every button belongs to the settings dialog, the error is shown as a toast
+    if !is_authorized(cx.requester, &message.chat, &cx.update.from).await? {
+        println!("rejected callback query from user {}", cx.update.from.id);
+        Err(AppError::NotAuthorized(chat_id, Some(cx.update.from.id)))?
+    }
+
+    let handle = CONVERSATIONS
+        .lock()
+        .await
+        .get(chat_id)
         .ok_or(Error::App(AppError::NoConversationRunning(chat_id)))?;
+    let mut conversation = handle.lock().await;
+    let conversation = &mut *conversation;
     let settings = &mut conversation.settings;
 
     macro_rules! answer_callback_query {
@@ -131,13 +490,25 @@ async fn handle_callback_query(cx: UpdateWithCx<&Bot, CallbackQuery>) -> Result
     }
 
     match cx.update.data.as_deref() {
-        Some(Settings::SETTINGS_CYCLE_MODEL) => {
+        Some(Settings::SETTINGS_PICK_MODEL) => {
             println!(
                 "editing setting \"model\", current value: {:?}",
                 settings.model
             );
 
-            let new_model = settings.cycle_model();
+            cx.requester
+                .edit_message_text(chat_id, message.id, get_model_picker_dialog_text(settings))
+                .reply_markup(get_model_picker_markup(settings))
+                .send()
+                .await?;
+        }
+        Some(Settings::SETTINGS_TOGGLE_TRAILING_SPACE) => {
+            println!(
+                "editing setting \"trailing space\", current value: {:?}",
+                settings.trailing_space_in_prompt
+            );
+
+            settings.trailing_space_in_prompt = !settings.trailing_space_in_prompt;
 
             cx.requester
                 .edit_message_reply_markup(chat_id, message.id)
@@ -145,32 +516,62 @@ async fn handle_callback_query(cx: UpdateWithCx<&Bot, CallbackQuery>) -> Result
                 .send()
                 .await?;
 
-            println!("set model to {:?}", new_model);
-            answer_callback_query!(format!("Set model to: {:?}", new_model));
+            println!(
+                "set trailing space to {:?}",
+                settings.trailing_space_in_prompt
+            );
+            answer_callback_query!(format!(
+                "Set trailing space to: {:?}",
+                settings.trailing_space_in_prompt
+            ));
         }
-        Some(Settings::SETTINGS_EDIT_TEMPERATURE) => {
+        Some(Settings::SETTINGS_TOGGLE_PROMPT_FORMAT) => {
             println!(
-                "editing setting \"temperature\", current value: {:.1}",
-                settings.temperature
+                "editing setting \"prompt format\", current value: {:?}",
+                settings.prompt_format
             );
 
+            let new_format = settings.toggle_prompt_format();
+
             cx.requester
-                .edit_message_text(
-                    chat_id,
-                    message.id,
-                    get_temperature_editor_dialog_text(settings.temperature),
-                )
-                .reply_markup(get_temperature_editor_markup())
+                .edit_message_reply_markup(chat_id, message.id)
+                .reply_markup(settings.get_inline_keyboard_markup())
                 .send()
                 .await?;
+
+            println!("set prompt format to {:?}", new_format);
+            answer_callback_query!(format!("Set prompt format to: {:?}", new_format));
         }
-        Some(Settings::SETTINGS_TOGGLE_TRAILING_SPACE) => {
+        Some(Settings::SETTINGS_TOGGLE_SUMMARIZE_HISTORY) => {
             println!(
-                "editing setting \"trailing space\", current value: {:?}",
-                settings.trailing_space_in_prompt
+                "editing setting \"summarize history\", current value: {:?}",
+                settings.summarize_history
             );
 
-            settings.trailing_space_in_prompt = !settings.trailing_space_in_prompt;
+            settings.summarize_history = !settings.summarize_history;
+
+            cx.requester
+                .edit_message_text(chat_id, message.id, settings.get_message_text())
+                .reply_markup(settings.get_inline_keyboard_markup())
+                .send()
+                .await?;
+
+            println!(
+                "set summarize history to {:?}",
+                settings.summarize_history
+            );
+            answer_callback_query!(format!(
+                "Set summarize history to: {:?}",
+                settings.summarize_history
+            ));
+        }
+        Some(Settings::SETTINGS_CYCLE_REPETITION_RECOVERY) => {
+            println!(
+                "editing setting \"repetition recovery\", current value: {:?}",
+                settings.repetition_recovery
+            );
+
+            let new_recovery = settings.cycle_repetition_recovery();
 
             cx.requester
                 .edit_message_reply_markup(chat_id, message.id)
@@ -178,18 +579,128 @@ async fn handle_callback_query(cx: UpdateWithCx<&Bot, CallbackQuery>) -> Result
                 .send()
                 .await?;
 
+            println!("set repetition recovery to {:?}", new_recovery);
+            answer_callback_query!(format!("Set repetition recovery to: {:?}", new_recovery));
+        }
+        Some(Settings::SETTINGS_CYCLE_REPLY_DELAY) => {
             println!(
-                "set trailing space to {:?}",
-                settings.trailing_space_in_prompt
+                "editing setting \"reply delay\", current value: {:?}",
+                settings.reply_delay_secs
+            );
+
+            let new_delay = settings.cycle_reply_delay();
+
+            cx.requester
+                .edit_message_reply_markup(chat_id, message.id)
+                .reply_markup(settings.get_inline_keyboard_markup())
+                .send()
+                .await?;
+
+            println!("set reply delay to {:?}", new_delay);
+            answer_callback_query!(format!("Set reply delay to: {}s", new_delay));
+        }
+        Some(Settings::SETTINGS_CYCLE_FAREWELL_DETECTION) => {
+            println!(
+                "editing setting \"end on farewell\", current value: {:?}",
+                settings.farewell_detection
+            );
+
+            let new_detection = settings.cycle_farewell_detection();
+
+            cx.requester
+                .edit_message_reply_markup(chat_id, message.id)
+                .reply_markup(settings.get_inline_keyboard_markup())
+                .send()
+                .await?;
+
+            println!("set farewell detection to {:?}", new_detection);
+            answer_callback_query!(format!("Set end on farewell to: {:?}", new_detection));
+        }
+        Some(Settings::SETTINGS_CYCLE_REPLY_POLICY) => {
+            println!(
+                "editing setting \"reply policy\", current value: {:?}",
+                settings.reply_policy
+            );
+
+            let new_policy = settings.cycle_reply_policy();
+
+            cx.requester
+                .edit_message_reply_markup(chat_id, message.id)
+                .reply_markup(settings.get_inline_keyboard_markup())
+                .send()
+                .await?;
+
+            println!("set reply policy to {:?}", new_policy);
+            answer_callback_query!(format!("Set reply policy to: {:?}", new_policy));
+        }
+        Some(Settings::SETTINGS_CYCLE_REPLY_PROBABILITY) => {
+            println!(
+                "editing setting \"reply probability\", current value: {:?}",
+                settings.reply_probability
             );
+
+            let new_probability = settings.cycle_reply_probability();
+
+            cx.requester
+                .edit_message_reply_markup(chat_id, message.id)
+                .reply_markup(settings.get_inline_keyboard_markup())
+                .send()
+                .await?;
+
+            println!("set reply probability to {:?}", new_probability);
             answer_callback_query!(format!(
-                "Set trailing space to: {:?}",
-                settings.trailing_space_in_prompt
+                "Set reply probability to: {:.0}%",
+                new_probability * 100.
             ));
         }
+        Some(Settings::SETTINGS_CYCLE_SPEAKER_LABELS) => {
+            println!(
+                "editing setting \"speaker labels\", current value: {:?}",
+                settings.speaker_labels
+            );
+
+            let new_labels = settings.cycle_speaker_labels();
+
+            cx.requester
+                .edit_message_reply_markup(chat_id, message.id)
+                .reply_markup(settings.get_inline_keyboard_markup())
+                .send()
+                .await?;
+
+            println!("set speaker labels to {:?}", new_labels);
+            answer_callback_query!(format!("Set speaker labels to: {:?}", new_labels));
+        }
         Some(Settings::SETTINGS_EDIT_STOP_TOKENS) => {
-            // TODO
-            println!("editing setting \"stop tokens\"");
+            println!(
+                "editing setting \"stop tokens\", current value: {:?}",
+                settings.stop_tokens
+            );
+
+            cx.requester
+                .edit_message_text(
+                    chat_id,
+                    message.id,
+                    get_stop_tokens_editor_dialog_text(settings),
+                )
+                .reply_markup(get_stop_tokens_editor_markup(settings))
+                .send()
+                .await?;
+        }
+        Some(Settings::SETTINGS_EDIT_PERSONA) => {
+            println!(
+                "editing setting \"persona\", current value: {:?}",
+                settings.persona
+            );
+
+            cx.requester
+                .edit_message_text(
+                    chat_id,
+                    message.id,
+                    get_persona_editor_dialog_text(settings),
+                )
+                .reply_markup(get_persona_editor_markup(settings))
+                .send()
+                .await?;
         }
         Some(Settings::SETTINGS_EDIT_BOT_NAME) => {
             println!("editing setting \"bot name\"");
@@ -205,13 +716,16 @@ async fn handle_callback_query(cx: UpdateWithCx<&Bot, CallbackQuery>) -> Result
                 async fn handle_message(&self, cx: UpdateWithCx<&Bot, Message>) -> Result<bool> {
                     if let Some(new_name) = cx.update.text() {
                         println!("setting bot name to \"{}\"", new_name);
-                        let mut conversations = CONVERSATIONS.lock().await;
-                        println!("locked conversations");
-                        let conversation = conversations
-                            .get_mut(self.0)
+                        let handle = CONVERSATIONS
+                            .lock()
+                            .await
+                            .get(self.0)
                             .ok_or(Error::App(AppError::NoConversationRunning(self.0)))?;
+                        let mut conversation = handle.lock().await;
+                        println!("locked conversation");
                         let settings = &mut conversation.settings;
                         settings.bot_name = new_name.to_string();
+                        save_conversation(self.0, &handle, &conversation).await?;
                         Ok(true)
                     } else {
                         cx.answer("Bot name must be text").send().await?;
@@ -229,21 +743,48 @@ async fn handle_callback_query(cx: UpdateWithCx<&Bot, CallbackQuery>) -> Result
                 .await?;
             answer_callback_query!("Done editing settings");
         }
-        Some(data) => {
-            handle_temperature_editor_callback_query(&cx, data, chat_id, message.id, settings)
+        Some(data) if data.starts_with("model_") => {
+            handle_model_picker_callback_query(&cx, data, chat_id, message.id, settings).await?
+        }
+        Some(data) if data.starts_with("stop_") => {
+            handle_stop_tokens_editor_callback_query(&cx, data, chat_id, message.id, conversation)
+                .await?
+        }
+        Some(data) if data.starts_with("persona_") => {
+            handle_persona_editor_callback_query(&cx, data, chat_id, message.id, conversation)
                 .await?
         }
+        Some(data) if data.starts_with(NUMERIC_EDITOR_PREFIX) => {
+            handle_numeric_editor_callback_query(&cx, data, chat_id, message.id, settings).await?
+        }
+        Some(data) => {
+            return Err(Error::App(AppError::UnexpectedCallbackQueryData(
+                data.to_string(),
+            )))
+        }
         None => return Err(Error::App(AppError::NoCallbackQueryData)),
     }
 
+    save_conversation(chat_id, &handle, conversation).await?;
+
     Ok(())
 }
 
 pub async fn callback_queries_handler(rx: DispatcherHandlerRx<&Bot, CallbackQuery>) {
     UnboundedReceiverStream::new(rx)
         .for_each_concurrent(None, |callback| async move {
+            let bot = callback.requester;
+            let query_id = callback.update.id.clone();
+            let context = ErrorContext {
+                handler: "callback_query",
+                chat_id: callback.update.message.as_ref().map(|m| m.chat_id()),
+                update_id: Some(query_id.clone()),
+            };
             let result = handle_callback_query(callback).await;
-            ERROR_LOGGER.lock().await.maybe_log(&result);
+            if let Err(e) = &result {
+                report_callback_error(bot, query_id, e).await;
+            }
+            ERROR_LOGGER.lock().await.maybe_log(&result, context);
         })
         .await;
 }
@@ -0,0 +1,286 @@
+use crate::result::Result;
+use crate::CONFIG;
+use itertools::Itertools;
+use std::fmt::{self, Display, Formatter};
+use std::iter;
+use teloxide::prelude::*;
+use teloxide::types::{BotCommand, BotCommandScope, Message, MessageEntityKind};
+
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub args: &'static str,
+    // english, used when there is no translation for the user's language
+    pub description: &'static str,
+    // (language code, description)
+    pub translations: &'static [(&'static str, &'static str)],
+    pub in_private_chats: bool,
+    pub in_group_chats: bool,
+    // only chat admins and owners may use it when access is restricted in the config
+    pub restricted: bool,
+}
+
+impl CommandInfo {
+    fn description(&self, language: Option<&str>) -> &'static str {
+        language
+            .and_then(|language| self.translations.iter().find(|(l, _)| *l == language))
+            .map_or(self.description, |(_, description)| description)
+    }
+}
+
+// languages that have translations, commands are registered once per language
+const LANGUAGES: &[&str] = &["de"];
+
+// every command the bot understands, used to generate /help and the command menu
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "begin",
+        args: "[bot name]",
+        description: "start a conversation, optionally giving the bot a name",
+        translations: &[("de", "ein Gespräch beginnen, optional mit einem Namen für den Bot")],
+        in_private_chats: true,
+        in_group_chats: true,
+        restricted: false,
+    },
+    CommandInfo {
+        name: "end",
+        args: "",
+        description: "end the conversation",
+        translations: &[("de", "das Gespräch beenden")],
+        in_private_chats: true,
+        in_group_chats: true,
+        restricted: true,
+    },
+    CommandInfo {
+        name: "status",
+        args: "",
+        description: "show how long the conversation has been running and some stats",
+        translations: &[("de", "zeigen, wie lange das Gespräch schon läuft, und Statistiken")],
+        in_private_chats: true,
+        in_group_chats: true,
+        restricted: false,
+    },
+    CommandInfo {
+        name: "settings",
+        args: "",
+        description: "edit the settings of the conversation",
+        translations: &[("de", "die Einstellungen des Gesprächs bearbeiten")],
+        in_private_chats: true,
+        in_group_chats: true,
+        restricted: true,
+    },
+    CommandInfo {
+        name: "reset",
+        args: "[messages to keep]",
+        description: "make the bot forget the conversation so far",
+        translations: &[("de", "den Bot das bisherige Gespräch vergessen lassen")],
+        in_private_chats: true,
+        in_group_chats: true,
+        restricted: true,
+    },
+    CommandInfo {
+        name: "nick",
+        args: "[nickname]",
+        description: "set the name the bot knows you by, leave empty to use your telegram name",
+        translations: &[(
+            "de",
+            "den Namen festlegen, unter dem der Bot dich kennt, leer lassen für deinen Telegram-Namen",
+        )],
+        // nicknames only help tell apart several people
+        in_private_chats: false,
+        in_group_chats: true,
+        restricted: false,
+    },
+    CommandInfo {
+        name: "quota",
+        args: "",
+        description: "show how much of today's usage limits is used up",
+        translations: &[("de", "anzeigen, wie viel der heutigen Nutzungslimits verbraucht ist")],
+        in_private_chats: true,
+        in_group_chats: true,
+        restricted: false,
+    },
+    CommandInfo {
+        name: "help",
+        args: "",
+        description: "show this message",
+        translations: &[("de", "diese Nachricht anzeigen")],
+        in_private_chats: true,
+        in_group_chats: true,
+        restricted: false,
+    },
+];
+
+// push the command menu to telegram, one list per scope and language
+pub async fn register_commands(bot: &Bot) -> Result {
+    let scopes: [(BotCommandScope, fn(&CommandInfo) -> bool); 2] = [
+        (BotCommandScope::AllPrivateChats, |c| c.in_private_chats),
+        (BotCommandScope::AllGroupChats, |c| c.in_group_chats),
+    ];
+
+    for (scope, available) in scopes {
+        for language in iter::once(None).chain(LANGUAGES.iter().copied().map(Some)) {
+            let commands = COMMANDS
+                .iter()
+                .filter(|c| available(c))
+                .map(|c| BotCommand::new(c.name, c.description(language)))
+                .collect_vec();
+
+            let mut request = bot.set_my_commands(commands).scope(scope.clone());
+            if let Some(language) = language {
+                request = request.language_code(language);
+            }
+            request.send().await?;
+        }
+    }
+
+    println!("registered bot commands");
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Begin { bot_name: Option<String> },
+    End,
+    Status,
+    Settings,
+    Reset { keep_last: usize },
+    Nick { nickname: Option<String> },
+    Quota,
+    Help,
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    // the command is for another bot in the same group, it's ignored rather than reported
+    OtherBot(String),
+    UnknownCommand(String),
+    UnexpectedArguments(&'static str),
+    InvalidArgument {
+        command: &'static str,
+        argument: String,
+        expected: &'static str,
+    },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        use ParseError::*;
+        match self {
+            OtherBot(username) => write!(f, "This command is for @{}", username),
+            UnknownCommand(name) => write!(f, "Unknown command /{}, see /help", name),
+            UnexpectedArguments(command) => write!(f, "/{} doesn't take any arguments", command),
+            InvalidArgument {
+                command,
+                argument,
+                expected,
+            } => write!(
+                f,
+                "Invalid argument \"{}\" for /{}, expected {}",
+                argument, command, expected
+            ),
+        }
+    }
+}
+
+// only lists the commands available in the kind of chat the help was asked for in
+pub fn help_text(language: Option<&str>, private_chat: bool) -> String {
+    COMMANDS
+        .iter()
+        .filter(|c| {
+            if private_chat {
+                c.in_private_chats
+            } else {
+                c.in_group_chats
+            }
+        })
+        .map(|c| {
+            let line = if c.args.is_empty() {
+                format!("/{} - {}", c.name, c.description(language))
+            } else {
+                format!("/{} {} - {}", c.name, c.args, c.description(language))
+            };
+            if c.restricted && CONFIG.access.restrict_to_admins {
+                line + " (admins only)"
+            } else {
+                line
+            }
+        })
+        .join("\n")
+}
+
+fn no_arguments(command: &'static str, args: &str, parsed: Command) -> Result<Command, ParseError> {
+    if args.is_empty() {
+        Ok(parsed)
+    } else {
+        Err(ParseError::UnexpectedArguments(command))
+    }
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        use Command::*;
+        match self {
+            Begin { .. } => "begin",
+            End => "end",
+            Status => "status",
+            Settings => "settings",
+            Reset { .. } => "reset",
+            Nick { .. } => "nick",
+            Quota => "quota",
+            Help => "help",
+        }
+    }
+
+    pub fn info(&self) -> &'static CommandInfo {
+        COMMANDS
+            .iter()
+            .find(|c| c.name == self.name())
+            .expect("every command is listed in COMMANDS")
+    }
+
+    // returns None if the message isn't a command, unknown commands are only reported if they're
+    // explicitly addressed to this bot
+    pub fn parse(message: &Message, bot_username: &str) -> Option<Result<Self, ParseError>> {
+        let text = message.text()?;
+        let entity = message
+            .entities()?
+            .iter()
+            .find(|e| e.offset == 0 && matches!(e.kind, MessageEntityKind::BotCommand))?;
+
+        // commands are ascii so the utf-16 length of the entity is also its length in bytes
+        let (command, args) = text.split_at(entity.length.min(text.len()));
+        let args = args.trim();
+        let (name, addressed) = match command.trim_start_matches('/').split_once('@') {
+            Some((name, username)) if username.eq_ignore_ascii_case(bot_username) => (name, true),
+            Some((_, username)) => return Some(Err(ParseError::OtherBot(username.to_string()))),
+            None => (command.trim_start_matches('/'), false),
+        };
+
+        let command = match name.to_lowercase().as_str() {
+            "begin" => Ok(Self::Begin {
+                bot_name: Some(args.to_string()).filter(|a| !a.is_empty()),
+            }),
+            "end" => no_arguments("end", args, Self::End),
+            "status" => no_arguments("status", args, Self::Status),
+            "settings" => no_arguments("settings", args, Self::Settings),
+            "reset" if args.is_empty() => Ok(Self::Reset { keep_last: 0 }),
+            "reset" => args
+                .parse()
+                .map(|keep_last| Self::Reset { keep_last })
+                .map_err(|_| ParseError::InvalidArgument {
+                    command: "reset",
+                    argument: args.to_string(),
+                    expected: "a number of messages",
+                }),
+            "nick" => Ok(Self::Nick {
+                nickname: Some(args.to_string()).filter(|a| !a.is_empty()),
+            }),
+            "quota" => no_arguments("quota", args, Self::Quota),
+            "help" => no_arguments("help", args, Self::Help),
+            _ if addressed => Err(ParseError::UnknownCommand(name.to_string())),
+            _ => return None,
+        };
+
+        Some(command)
+    }
+}
@@ -0,0 +1,86 @@
+use crate::completion::CompletionError;
+use crate::result::{AppError, Error};
+use crate::ChatId;
+use futures::lock::Mutex;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+
+// at most one error message per chat in this interval so that a broken backend doesn't flood
+// a busy group, callback toasts are only shown to the user who pressed the button and aren't
+// limited
+const CHAT_ERROR_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref LAST_CHAT_ERROR: Mutex<HashMap<ChatId, Instant>> = Default::default();
+}
+
+// what users are told when handling their update failed, None means the error isn't worth
+// bothering them with
+fn user_message(error: &Error) -> Option<String> {
+    use AppError::*;
+    let message = match error {
+        Error::Request(_) => "Telegram didn't accept a request, please try again",
+        Error::Io(_) | Error::Serialization(_) => {
+            "Something went wrong saving the conversation, recent changes might be lost"
+        }
+        Error::Completion(CompletionError::CircuitOpen) => {
+            "The language model is unavailable right now, replies are paused for a minute"
+        }
+        Error::Completion(_) => "Couldn't come up with a reply, please try again later",
+        Error::App(ConversationAlreadyRunning(_)) => "A conversation is already running",
+        Error::App(NoConversationRunning(_)) => {
+            "No conversation is running, use /begin to start one"
+        }
+        Error::App(UnexpectedCallbackQueryData(_)) | Error::App(NoCallbackQueryData) => {
+            "This button doesn't work anymore, open /settings again"
+        }
+        Error::App(QuotaExceeded(limit)) => return Some(limit.to_string()),
+        Error::App(NotAuthorized(..)) => "Only admins of this chat can do that",
+        // messages from channels and anonymous admins, and updates that piled up while the bot
+        // was offline, are ignored on purpose
+        Error::App(MessageWithoutSender(..)) | Error::App(MessageTooOld) => return None,
+    };
+    Some(message.to_string())
+}
+
+// returns whether the chat may get another error message, and if so starts a new interval
+async fn allow_chat_error(chat_id: ChatId) -> bool {
+    let mut last_errors = LAST_CHAT_ERROR.lock().await;
+    let now = Instant::now();
+    match last_errors.get(&chat_id) {
+        Some(last) if now.duration_since(*last) < CHAT_ERROR_INTERVAL => false,
+        _ => {
+            last_errors.insert(chat_id, now);
+            true
+        }
+    }
+}
+
+// failures are only printed, reporting them would likely fail the same way
+pub async fn report_message_error(bot: &Bot, chat_id: ChatId, error: &Error) {
+    let text = match user_message(error) {
+        Some(text) => text,
+        None => return,
+    };
+    if !allow_chat_error(chat_id).await {
+        println!("not reporting error to chat {}, reported one recently", chat_id);
+        return;
+    }
+    if let Err(e) = bot.send_message(chat_id, text).send().await {
+        println!("failed to report error to chat {}: {:?}", chat_id, e);
+    }
+}
+
+// always answers the query, otherwise the button keeps showing a loading indicator
+pub async fn report_callback_error(bot: &Bot, query_id: String, error: &Error) {
+    let mut request = bot.answer_callback_query(query_id);
+    if let Some(text) = user_message(error) {
+        request = request.text(text);
+    }
+    // fails if the query was already answered before the error happened
+    if let Err(e) = request.send().await {
+        println!("failed to answer callback query after error: {:?}", e);
+    }
+}
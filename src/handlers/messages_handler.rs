@@ -1,15 +1,31 @@
+use crate::conversation::settings::{ReplyPolicy, Settings};
+use crate::conversation::Conversation;
+use crate::error_logging::ErrorContext;
+use crate::handlers::save_conversation;
+use crate::handlers::access::{ensure_message_authorized, is_message_authorized};
+use crate::handlers::error_replies::report_message_error;
+use crate::handlers::commands::{help_text, Command, ParseError};
 use crate::result::{Error, Result};
-use crate::{AppError, FromUser, CONVERSATIONS, ERROR_LOGGER, OPENAI_CLIENT};
+use crate::{
+    bot_username, AppError, FromUser, COMPLETION_BACKEND, CONVERSATIONS, ERROR_LOGGER, QUOTAS,
+    STORAGE,
+};
 use futures::lock::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 use lazy_static::lazy_static;
 use teloxide::prelude::*;
-use teloxide::types::MessageKind;
+use teloxide::types::{ChatAction, MessageKind};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::time::sleep;
 use crate::ChatId;
 use async_trait::async_trait;
 
+const TYPING_REFRESH_INTERVAL: Duration = Duration::from_secs(4);
+
 #[async_trait]
 pub trait SpecialHandler: Send + Sync {
     /// return value says whether to remove the handler or not
@@ -20,7 +36,6 @@ lazy_static! {
     pub static ref SPECIAL_HANDLERS: Mutex<HashMap<ChatId, Box<dyn SpecialHandler>>> = Default::default();
 }
 
-// TODO: give the bot the ability to end a conversation if it says "bye" or "goodbye"
 async fn handle_message(cx: UpdateWithCx<&Bot, Message>) -> Result {
     let fresh = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -35,115 +50,362 @@ async fn handle_message(cx: UpdateWithCx<&Bot, Message>) -> Result {
         Err(AppError::MessageTooOld)?
     }
 
+    let chat_id = cx.update.chat_id();
+
+    // only hold the global lock long enough to look up this chat's conversation
+    let conversation = CONVERSATIONS.lock().await.get(chat_id);
+
     // if there is a settings dialog active, deactivate it to prevent inconsistencies
-    if let Some(conversation) = CONVERSATIONS.lock().await.get_mut(cx.update.chat_id()) {
-        conversation
-            .deactivate_settings_dialog(cx.requester)
-            .await?;
+    if let Some(handle) = &conversation {
+        let mut conversation = handle.lock().await;
+        if conversation.active_settings_dialog.is_some() {
+            conversation
+                .deactivate_settings_dialog(cx.requester)
+                .await?;
+            save_conversation(chat_id, &handle, &conversation).await?;
+        }
     }
 
     if let MessageKind::Common(_) = cx.update.kind {
-        // if a special handler is defined for this chat, invoke it and remove it
-        let mut special_handlers = SPECIAL_HANDLERS.lock().await;
-        let chat_id = cx.update.chat_id();
-        if let Some(special_handler) = special_handlers.get(&chat_id) {
+        // special handlers are opened from the settings dialog, messages from users who couldn't
+        // have opened it are handled as usual
+        let has_special_handler = SPECIAL_HANDLERS.lock().await.contains_key(&chat_id);
+        let special_handler_allowed =
+            has_special_handler && is_message_authorized(cx.requester, &cx.update).await?;
+
+        // if a special handler is defined for this chat, invoke it and remove it, it's taken out
+        // of the map while it runs so that a slow handler doesn't hold up every other chat, and
+        // put back unless it's done (a handler installed in the meantime takes precedence)
+        let special_handler = if special_handler_allowed {
+            SPECIAL_HANDLERS.lock().await.remove(&chat_id)
+        } else {
+            None
+        };
+        if let Some(special_handler) = special_handler {
             println!("message passed to special handler");
-            if special_handler.handle_message(cx).await? {
-                special_handlers.remove(&chat_id);
+            let result = special_handler.handle_message(cx).await;
+            if !matches!(result, Ok(true)) {
+                SPECIAL_HANDLERS
+                    .lock()
+                    .await
+                    .entry(chat_id)
+                    .or_insert(special_handler);
             }
+            result?;
         } else {
-            match cx.update.text() {
-                Some("/begin" | "/begin@nonautisticbot") => {
-                    println!("got /begin command");
-                    match CONVERSATIONS.lock().await.begin(cx.update.chat_id()) {
-                        Ok(_) => {
-                            cx.answer("Hello").send().await?;
-                        }
-                        Err(Error::App(AppError::ConversationAlreadyRunning(_))) => {
-                            cx.answer("Conversation already running").send().await?;
-                            // TODO: since <timestamp>
-                        }
-                        res => res?,
-                    }
+            match Command::parse(&cx.update, bot_username()) {
+                Some(Ok(command)) => handle_command(&cx, command, conversation).await?,
+                Some(Err(ParseError::OtherBot(_))) => {}
+                Some(Err(e)) => {
+                    println!("failed to parse command: {:?}", e);
+                    cx.answer(e.to_string()).send().await?;
                 }
-                Some("/end" | "/end@nonautisticbot") => {
-                    println!("got /end command");
-                    match CONVERSATIONS.lock().await.end(cx.chat_id()) {
-                        Ok(_) => {
-                            cx.answer("Goodbye").send().await?;
-                        }
-                        Err(Error::App(AppError::NoConversationRunning(_))) => {
-                            cx.answer("No conversation currently running")
-                                .send()
-                                .await?;
-                        }
-                        res => res?,
+                None => {
+                    if let Some(msg) = cx.update.text() {
+                        handle_text(&cx, msg, conversation).await?;
                     }
                 }
-                // TODO: make settings per-chat
-                Some("/settings" | "/settings@nonautisticbot") => {
-                    println!("got /settings command");
-                    match CONVERSATIONS.lock().await.get_mut(cx.chat_id()) {
-                        None => {
-                            cx.answer(
-                                "Settings are per-conversation, no conversation currently running",
-                            )
-                                .send()
-                                .await?;
-                        }
-                        Some(conversation) => {
-                            let message = cx
-                                .requester
-                                .send_message(cx.chat_id(), conversation.settings.get_message_text())
-                                .reply_markup(conversation.settings.get_inline_keyboard_markup())
-                                .send()
-                                .await?;
-
-                            conversation.active_settings_dialog = Some((cx.chat_id(), message.id));
-                        }
-                    }
-                }
-                Some("/reset" | "/reset@nonautisticbot") => {
-                    println!("got /reset command");
-                    match CONVERSATIONS.lock().await.get_mut(cx.chat_id()) {
-                        None => {
-                            cx.answer("No conversation currently running")
-                                .send()
-                                .await?;
-                        }
-                        Some(conversation) => {
-                            conversation.clear_history();
-                            println!("cleared history");
-                            cx.answer("Reset bot memory").send().await?;
-                        }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// the bot name has to appear as a whole word, the @username anywhere, the default name is an
+// everyday word so only the @username counts until the bot is given a name
+fn mentions_bot(msg: &str, bot_name: &str) -> bool {
+    let msg = msg.to_lowercase();
+    let username = format!("@{}", bot_username().to_lowercase());
+    if msg.contains(&username) {
+        return true;
+    }
+    if bot_name == Settings::DEFAULT_BOT_NAME || bot_name.is_empty() {
+        return false;
+    }
+
+    let bot_name = bot_name.to_lowercase();
+    msg.match_indices(&bot_name).any(|(i, _)| {
+        let before = msg[..i].chars().next_back();
+        let after = msg[i + bot_name.len()..].chars().next();
+        !before.map_or(false, char::is_alphanumeric) && !after.map_or(false, char::is_alphanumeric)
+    })
+}
+
+fn wants_reply(message: &Message, msg: &str, settings: &Settings) -> bool {
+    if message.chat.is_private() {
+        return true;
+    }
+
+    match settings.reply_policy {
+        ReplyPolicy::Always => true,
+        ReplyPolicy::Mentioned => mentions_bot(msg, &settings.bot_name),
+        ReplyPolicy::RepliedTo => message
+            .reply_to_message()
+            .and_then(|m| m.from())
+            .and_then(|u| u.username.as_deref())
+            .map_or(false, |username| username == bot_username()),
+        ReplyPolicy::Random => rand::random::<f64>() < settings.reply_probability,
+    }
+}
+
+async fn handle_command(
+    cx: &UpdateWithCx<&Bot, Message>,
+    command: Command,
+    conversation: Option<Arc<Mutex<Conversation>>>,
+) -> Result {
+    let chat_id = cx.chat_id();
+    println!("got command {:?}", command);
+
+    if command.info().restricted {
+        ensure_message_authorized(cx.requester, &cx.update).await?;
+    }
+
+    match command {
+        Command::Begin { bot_name } => {
+            let result = CONVERSATIONS.lock().await.begin(chat_id);
+            match result {
+                Ok(handle) => {
+                    let mut conversation = handle.lock().await;
+                    if let Some(bot_name) = bot_name {
+                        conversation.settings.bot_name = bot_name;
                     }
+                    save_conversation(chat_id, &handle, &conversation).await?;
+                    cx.answer("Hello").send().await?;
                 }
-                Some(msg) => {
-                    println!("got message \"{}\"", msg);
-                    let user = match cx.update.from() {
-                        Some(user) => {
-                            println!("sender: user: {}", user.first_name);
-                            FromUser::User(user.clone())
-                        }
-                        None => {
-                            println!("message without sender");
-                            Err(AppError::MessageWithoutSender(
-                                cx.chat_id(),
-                                msg.to_string(),
-                            ))?
-                        }
+                Err(Error::App(AppError::ConversationAlreadyRunning(_))) => {
+                    // same as /status, which starts with "Conversation running since ..."
+                    let text = match conversation {
+                        Some(conversation) => conversation.lock().await.get_status_text(),
+                        None => "Conversation already running".to_string(),
                     };
+                    cx.answer(text).send().await?;
+                }
+                Err(e) => Err(e)?,
+            }
+        }
+        Command::End => {
+            let result = CONVERSATIONS.lock().await.end(chat_id, &**STORAGE);
+            match result {
+                Ok(()) => {
+                    cx.answer("Goodbye").send().await?;
+                }
+                Err(Error::App(AppError::NoConversationRunning(_))) => {
+                    cx.answer("No conversation currently running")
+                        .send()
+                        .await?;
+                }
+                res => res?,
+            }
+        }
+        Command::Status => match conversation {
+            None => {
+                cx.answer("No conversation currently running")
+                    .send()
+                    .await?;
+            }
+            Some(conversation) => {
+                let text = conversation.lock().await.get_status_text();
+                cx.answer(text).send().await?;
+            }
+        },
+        Command::Settings => match conversation {
+            None => {
+                cx.answer("Settings are per-conversation, no conversation currently running")
+                    .send()
+                    .await?;
+            }
+            Some(handle) => {
+                let mut conversation = handle.lock().await;
+                let message = cx
+                    .requester
+                    .send_message(chat_id, conversation.settings.get_message_text())
+                    .reply_markup(conversation.settings.get_inline_keyboard_markup())
+                    .send()
+                    .await?;
 
-                    if let Some(conversation) = CONVERSATIONS.lock().await.get_mut(cx.chat_id()) {
-                        conversation.add(user.clone(), msg.to_string());
-                        let reply = conversation
-                            .produce_reply(&*OPENAI_CLIENT.lock().await)
-                            .await?;
-                        cx.answer(reply).send().await?;
-                    }
+                conversation.active_settings_dialog = Some((chat_id, message.id));
+                save_conversation(chat_id, &handle, &conversation).await?;
+            }
+        },
+        Command::Reset { keep_last } => match conversation {
+            None => {
+                cx.answer("No conversation currently running")
+                    .send()
+                    .await?;
+            }
+            Some(handle) => {
+                let mut conversation = handle.lock().await;
+                conversation.clear_history(keep_last);
+                save_conversation(chat_id, &handle, &conversation).await?;
+                println!("cleared history, kept {} message(s)", keep_last);
+                if keep_last == 0 {
+                    cx.answer("Reset bot memory").send().await?;
+                } else {
+                    cx.answer(format!(
+                        "Reset bot memory, kept the last {} message(s)",
+                        keep_last
+                    ))
+                    .send()
+                    .await?;
                 }
-                _ => {}
             }
+        },
+        Command::Nick { nickname } => match (conversation, cx.update.from()) {
+            (None, _) => {
+                cx.answer("Nicknames are per-conversation, no conversation currently running")
+                    .send()
+                    .await?;
+            }
+            (Some(_), None) => {
+                cx.answer("Can't tell who sent this message").send().await?;
+            }
+            (Some(handle), Some(user)) => {
+                let mut conversation = handle.lock().await;
+                conversation.set_nickname(user, nickname.clone());
+                save_conversation(chat_id, &handle, &conversation).await?;
+                let label = conversation.participant_label(user.id);
+                drop(conversation);
+                println!("set nickname of {} to {:?}", user.id, nickname);
+                cx.answer(format!("The bot now knows you as {}", label))
+                    .send()
+                    .await?;
+            }
+        },
+        Command::Quota => {
+            let user = cx.update.from().map(|u| u.id);
+            let text = QUOTAS.lock().await.get_message_text(chat_id, user);
+            cx.answer(text).send().await?;
+        }
+        Command::Help => {
+            let language = cx.update.from().and_then(|u| u.language_code.as_deref());
+            cx.answer(help_text(language, cx.update.chat.is_private()))
+                .send()
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_text(
+    cx: &UpdateWithCx<&Bot, Message>,
+    msg: &str,
+    conversation: Option<Arc<Mutex<Conversation>>>,
+) -> Result {
+    println!("got message \"{}\"", msg);
+    let (user, user_id) = match cx.update.from() {
+        Some(user) => {
+            println!("sender: user: {}", user.first_name);
+            (FromUser::User(user.clone()), user.id)
+        }
+        None => {
+            println!("message without sender");
+            Err(AppError::MessageWithoutSender(
+                cx.chat_id(),
+                msg.to_string(),
+            ))?
+        }
+    };
+
+    let handle = match conversation {
+        Some(conversation) => conversation,
+        None => return Ok(()),
+    };
+
+    // every message goes into the history right away, only the reply is delayed
+    let (generation, delay) = {
+        let mut conversation = handle.lock().await;
+        conversation.add(user, msg.to_string());
+        save_conversation(cx.chat_id(), &handle, &conversation).await?;
+        // messages that don't warrant a reply don't cancel a pending one either
+        if !wants_reply(&cx.update, msg, &conversation.settings) {
+            println!("reply policy says not to reply");
+            return Ok(());
+        }
+        QUOTAS.lock().await.check_user_message(user_id)?;
+        (
+            conversation.next_reply_generation(),
+            conversation.settings.reply_delay_secs,
+        )
+    };
+
+    // bots can't see when users are typing, instead wait for a quiet period so that a burst of
+    // messages (possibly from several users) gets a single reply
+    if delay > 0 {
+        sleep(Duration::from_secs(delay)).await;
+        if !handle.lock().await.is_latest_reply_generation(generation) {
+            println!("newer message arrived, not replying");
+            return Ok(());
+        }
+    }
+
+    // messages within the same chat are serialized by the conversation lock,
+    // other chats are unaffected while the completion is in flight
+    let mut conversation = handle.lock().await;
+    if delay > 0 && !conversation.is_latest_reply_generation(generation) {
+        println!("newer message arrived, not replying");
+        return Ok(());
+    }
+
+    // limits are checked before the request, a reply can exceed them by its own size
+    QUOTAS.lock().await.check_budget(cx.chat_id())?;
+    let tokens_before = conversation.stats.tokens_used;
+
+    // the typing status expires after a few seconds, keep refreshing it until the reply is ready
+    let typing = async {
+        loop {
+            if let Err(e) = cx
+                .requester
+                .send_chat_action(cx.chat_id(), ChatAction::Typing)
+                .send()
+                .await
+            {
+                println!("failed to send typing action: {:?}", e);
+            }
+            sleep(TYPING_REFRESH_INTERVAL).await;
+        }
+    };
+    let reply = select! {
+        reply = conversation.produce_reply(&**COMPLETION_BACKEND) => reply,
+        _ = typing => unreachable!(),
+    };
+    let farewell = match &reply {
+        Ok(reply) => conversation.is_farewell(reply, &**COMPLETION_BACKEND).await,
+        Err(_) => false,
+    };
+
+    // requests that were made count even if the reply failed in the end
+    let tokens = conversation.stats.tokens_used - tokens_before;
+    let cost = tokens as f64 * conversation.settings.model_spec().cost_per_token;
+    // the reply is already paid for, send it even if the usage couldn't be saved
+    if let Err(e) = QUOTAS.lock().await.record_usage(cx.chat_id(), tokens, cost) {
+        println!("failed to save quota usage: {:?}", e);
+    }
+    let reply = reply?;
+
+    save_conversation(cx.chat_id(), &handle, &conversation).await?;
+    if farewell {
+        conversation.deactivate_settings_dialog(cx.requester).await?;
+    }
+    drop(conversation);
+    cx.answer(reply).send().await?;
+
+    if farewell {
+        println!("bot said goodbye, ending conversation");
+        // the conversation might have been ended (and a new one begun) in the meantime
+        let result = CONVERSATIONS
+            .lock()
+            .await
+            .end_if_current(cx.chat_id(), &handle, &**STORAGE);
+        match result {
+            Ok(()) => {
+                cx.answer("The bot left the conversation, use /begin to start a new one")
+                    .send()
+                    .await?;
+            }
+            Err(Error::App(AppError::NoConversationRunning(_))) => {}
+            res => res?,
         }
     }
 
@@ -153,8 +415,18 @@ async fn handle_message(cx: UpdateWithCx<&Bot, Message>) -> Result {
 pub async fn messages_handler(rx: DispatcherHandlerRx<&Bot, Message>) {
     UnboundedReceiverStream::new(rx)
         .for_each_concurrent(None, |message| async move {
+            let bot = message.requester;
+            let chat_id = message.update.chat_id();
+            let context = ErrorContext {
+                handler: "message",
+                chat_id: Some(chat_id),
+                update_id: Some(message.update.id.to_string()),
+            };
             let result = handle_message(message).await;
-            ERROR_LOGGER.lock().await.maybe_log(&result);
+            if let Err(e) = &result {
+                report_message_error(bot, chat_id, e).await;
+            }
+            ERROR_LOGGER.lock().await.maybe_log(&result, context);
         })
         .await;
 }
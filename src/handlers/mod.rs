@@ -1,5 +1,28 @@
+mod access;
 mod callback_queries_handler;
+mod commands;
+mod error_replies;
 mod messages_handler;
+mod numeric_editor;
+
+use crate::conversation::Conversation;
+use crate::result::Result;
+use crate::{ChatId, CONVERSATIONS, STORAGE};
+use futures::lock::Mutex;
+use std::sync::Arc;
 
 pub use callback_queries_handler::callback_queries_handler;
+pub use commands::register_commands;
 pub use messages_handler::messages_handler;
+
+// skipped if the conversation was ended in the meantime, see Conversations::save
+async fn save_conversation(
+    chat_id: ChatId,
+    handle: &Arc<Mutex<Conversation>>,
+    conversation: &Conversation,
+) -> Result {
+    CONVERSATIONS
+        .lock()
+        .await
+        .save(chat_id, handle, conversation, &**STORAGE)
+}
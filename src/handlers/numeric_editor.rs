@@ -0,0 +1,137 @@
+use crate::conversation::settings::numeric::{NumericSetting, NUMERIC_EDITOR_PREFIX};
+use crate::conversation::settings::Settings;
+use crate::result::Result;
+use crate::{AppError, ChatId, MessageId};
+use itertools::Itertools;
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup};
+
+// callback data is "num_<key>_<action>", the action is "open", "back", "add<i>" or "sub<i>"
+// where i indexes the setting's steps
+const OPEN: &str = "open";
+const BACK: &str = "back";
+const ADD: &str = "add";
+const SUB: &str = "sub";
+
+fn callback_data(setting: &NumericSetting, action: &str) -> String {
+    format!("{}{}_{}", NUMERIC_EDITOR_PREFIX, setting.key, action)
+}
+
+// largest decrement first, then increments in ascending order
+fn get_numeric_editor_markup(setting: &NumericSetting) -> InlineKeyboardMarkup {
+    let decrements = setting.steps.iter().enumerate().rev().map(|(i, step)| {
+        (
+            format!("-{}", setting.format(*step)),
+            callback_data(setting, &format!("{}{}", SUB, i)),
+        )
+    });
+    let increments = setting.steps.iter().enumerate().map(|(i, step)| {
+        (
+            format!("+{}", setting.format(*step)),
+            callback_data(setting, &format!("{}{}", ADD, i)),
+        )
+    });
+    let button_row = decrements
+        .chain(increments)
+        .map(|(text, data)| {
+            InlineKeyboardButton::new(text, InlineKeyboardButtonKind::CallbackData(data))
+        })
+        .collect_vec();
+    let back_button = InlineKeyboardButton::new(
+        "back",
+        InlineKeyboardButtonKind::CallbackData(callback_data(setting, BACK)),
+    );
+    InlineKeyboardMarkup::new([button_row, vec![back_button]])
+}
+
+fn get_numeric_editor_dialog_text(setting: &NumericSetting, settings: &Settings) -> String {
+    format!(
+        "Editing {}\ncurrent value: {}\nrange: {} to {}",
+        setting.name,
+        setting.format_value(settings),
+        setting.format(setting.min),
+        setting.format(setting.max),
+    )
+}
+
+fn parse_callback_data(data: &str) -> Option<(&'static NumericSetting, &str)> {
+    let (key, action) = data.strip_prefix(NUMERIC_EDITOR_PREFIX)?.rsplit_once('_')?;
+    Some((NumericSetting::find(key)?, action))
+}
+
+pub async fn handle_numeric_editor_callback_query(
+    cx: &UpdateWithCx<&Bot, CallbackQuery>,
+    data: &str,
+    chat_id: ChatId,
+    message_id: MessageId,
+    settings: &mut Settings,
+) -> Result {
+    let unexpected = || AppError::UnexpectedCallbackQueryData(data.to_string());
+    let (setting, action) = parse_callback_data(data).ok_or_else(unexpected)?;
+
+    let delta = match action {
+        OPEN => {
+            println!(
+                "editing setting \"{}\", current value: {}",
+                setting.name,
+                setting.format_value(settings)
+            );
+
+            cx.requester
+                .edit_message_text(
+                    chat_id,
+                    message_id,
+                    get_numeric_editor_dialog_text(setting, settings),
+                )
+                .reply_markup(get_numeric_editor_markup(setting))
+                .send()
+                .await?;
+
+            return Ok(());
+        }
+        BACK => {
+            cx.requester
+                .edit_message_text(chat_id, message_id, settings.get_message_text())
+                .reply_markup(settings.get_inline_keyboard_markup())
+                .send()
+                .await?;
+
+            return Ok(());
+        }
+        action => {
+            let step = |i: &str| i.parse::<usize>().ok().and_then(|i| setting.steps.get(i));
+            if let Some(step) = action.strip_prefix(ADD).and_then(step) {
+                *step
+            } else if let Some(step) = action.strip_prefix(SUB).and_then(step) {
+                -*step
+            } else {
+                Err(unexpected())?
+            }
+        }
+    };
+    let new_value = setting.apply_delta(settings, delta);
+
+    cx.requester
+        .edit_message_text(
+            chat_id,
+            message_id,
+            get_numeric_editor_dialog_text(setting, settings),
+        )
+        .reply_markup(get_numeric_editor_markup(setting))
+        .send()
+        .await?;
+
+    println!("set {} to {:?}", setting.name, new_value);
+
+    cx.requester
+        .answer_callback_query(cx.update.id.clone())
+        .text(format!(
+            "Set {} to: {}",
+            setting.name,
+            setting.format(new_value)
+        ))
+        .send()
+        .await?;
+
+    Ok(())
+}
@@ -1,47 +1,97 @@
 #![feature(try_blocks)]
 #![deny(unused_must_use)]
 
+use crate::completion::{CompletionBackend, OpenAiBackend, RetryingBackend, ScriptedBackend};
+use crate::config::Config;
+use crate::conversation::persistence::{JsonFileStorage, Storage};
 use crate::conversation::FromUser;
-use crate::handlers::{callback_queries_handler, messages_handler};
+use crate::handlers::{callback_queries_handler, messages_handler, register_commands};
+use crate::quota::Quotas;
 use crate::result::AppError;
 use conversation::Conversations;
 use error_logging::ErrorLogger;
 use futures::lock::Mutex;
 use lazy_static::lazy_static;
-use openai_api::Client;
-use std::{env, fs};
+use once_cell::sync::OnceCell;
+use std::{env, fs, mem, process};
 use teloxide::prelude::*;
 use teloxide::Bot;
 use tokio::select;
 use tokio::signal::ctrl_c;
 
+mod completion;
+mod config;
 mod conversation;
 mod error_logging;
 mod handlers;
+mod quota;
 mod result;
 
 type ChatId = i64;
 type MessageId = i32;
+type UserId = i64;
+
+const CONFIG_PATH: &str = "config.json";
+const CONVERSATIONS_DIR: &str = "conversations";
+const QUOTA_PATH: &str = "quota.json";
+
+// fetched from telegram at startup
+static BOT_USERNAME: OnceCell<String> = OnceCell::new();
+
+fn bot_username() -> &'static str {
+    BOT_USERNAME.get().expect("bot username not fetched yet")
+}
 
 lazy_static! {
+    static ref CONFIG: Config = Config::load(CONFIG_PATH).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
     static ref ERROR_LOGGER: Mutex<ErrorLogger> = Mutex::new(ErrorLogger::new());
-    // one per chat
-    static ref OPENAI_CLIENT: Mutex<Client> = {
-        let token = fs::read_to_string("secrets/openai.token")
-            .expect("error reading openai token");
-        let client = Client::new(token.trim()).unwrap();
-        Mutex::new(client)
+    // shared by all chats, requests don't need exclusive access
+    // COMPLETION_BACKEND=echo or COMPLETION_BACKEND=scripted:<file> run the bot without network access
+    static ref COMPLETION_BACKEND: Box<dyn CompletionBackend> = {
+        match env::var("COMPLETION_BACKEND") {
+            Ok(backend) if backend == "echo" => Box::new(ScriptedBackend::echo()),
+            Ok(backend) if backend.starts_with("scripted:") => {
+                let script = fs::read_to_string(&backend["scripted:".len()..])
+                    .expect("error reading completion script");
+                Box::new(ScriptedBackend::new(script.lines().map(ToString::to_string)))
+            }
+            _ => {
+                let token = fs::read_to_string("secrets/openai.token")
+                    .expect("error reading openai token");
+                Box::new(RetryingBackend::new(
+                    OpenAiBackend::new(token.trim()),
+                    CONFIG.retry.clone(),
+                ))
+            }
+        }
     };
 
     // static ref RNG: Mutex<StdRng> = Mutex::new(StdRng::from_entropy());
-    static ref CONVERSATIONS: Mutex<Conversations> = Mutex::new(Conversations::new());
+    static ref STORAGE: Box<dyn Storage> = Box::new(JsonFileStorage::new(CONVERSATIONS_DIR));
+    static ref CONVERSATIONS: Mutex<Conversations> = Mutex::new(
+        Conversations::restore(&**STORAGE).unwrap_or_else(|e| {
+            eprintln!("failed to restore conversations: {:?}", e);
+            process::exit(1)
+        })
+    );
+    static ref QUOTAS: Mutex<Quotas> = Mutex::new(Quotas::load(QUOTA_PATH, CONFIG.limits.clone()));
 }
 
 async fn run_bot(bot: &'static Bot) {
     teloxide::enable_logging!();
 
-    // TODO: if someone is typing, wait to reply
-    // TODO: dynamically set bot commands at every launch, bypass botfather
+    let me = bot.get_me().send().await.expect("failed to get bot info");
+    let username = me.user.username.expect("bot has no username");
+    println!("running as @{}", username);
+    BOT_USERNAME.set(username).unwrap();
+
+    // the bot still works without the command menu, don't fail the launch
+    if let Err(e) = register_commands(bot).await {
+        eprintln!("failed to register bot commands: {:?}", e);
+    }
 
     Dispatcher::new(bot)
         .messages_handler(messages_handler)
@@ -52,6 +102,23 @@ async fn run_bot(bot: &'static Bot) {
 
 #[tokio::main]
 async fn main() {
+    // `conversation_bot errors ...` inspects the error log instead of running the bot
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        None => {}
+        Some("errors") => return error_logging::cli::run(args),
+        Some(arg) => {
+            eprintln!("unknown subcommand {:?}, expected no arguments or \"errors\"", arg);
+            return;
+        }
+    }
+
+    // a broken config or unreadable conversations should stop the bot right away rather than
+    // at the first message
+    lazy_static::initialize(&CONFIG);
+    lazy_static::initialize(&CONVERSATIONS);
+    lazy_static::initialize(&QUOTAS);
+
     lazy_static! {
         static ref BOT: Bot = {
             let token = fs::read_to_string("secrets/bot.token")
@@ -64,10 +131,13 @@ async fn main() {
         _ = run_bot(&BOT) => {},
         _ = ctrl_c() => {
             println!("interrupted");
-            if let Err(e) = CONVERSATIONS.lock().await.cleanup(&BOT).await {
+            let conversations = mem::take(&mut *CONVERSATIONS.lock().await);
+            if let Err(e) = conversations.cleanup(&BOT, &**STORAGE).await {
                 eprintln!("UNHANDLED ERROR CLEANING UP CONVERSATIONS: {:?}", e);
             }
-            ERROR_LOGGER.lock().await.flush().expect("failed to flush error file");
+            if let Err(e) = ERROR_LOGGER.lock().await.flush() {
+                eprintln!("failed to flush error file: {:?}", e);
+            }
         }
     };
 }
@@ -0,0 +1,196 @@
+use crate::config::LimitsConfig;
+use crate::conversation::persistence::write_json_atomically;
+use crate::result::{AppError, Result};
+use crate::{ChatId, UserId};
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum QuotaLimit {
+    // messages per minute for a single user
+    UserMessages(u32),
+    // tokens per day for a single chat
+    ChatTokens(u64),
+    // dollars per day over all chats
+    DailySpend(f64),
+}
+
+impl Display for QuotaLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        use QuotaLimit::*;
+        match self {
+            UserMessages(limit) => write!(
+                f,
+                "You're sending messages too fast, the bot replies to at most {} of your \
+                messages per minute",
+                limit
+            ),
+            ChatTokens(limit) => write!(
+                f,
+                "This chat used up its {} tokens for today, the bot will reply again after \
+                midnight UTC, see /quota",
+                limit
+            ),
+            DailySpend(cap) => write!(
+                f,
+                "The bot reached its spending limit of ${:.2} for today, it will reply again \
+                after midnight UTC",
+                cap
+            ),
+        }
+    }
+}
+
+// usage counters that reset every day (UTC)
+#[derive(Serialize, Deserialize)]
+struct DailyUsage {
+    day: NaiveDate,
+    chat_tokens: HashMap<ChatId, u64>,
+    // in dollars, estimated from the token counts
+    spend: f64,
+}
+
+impl DailyUsage {
+    fn today() -> Self {
+        Self {
+            day: Utc::now().naive_utc().date(),
+            chat_tokens: HashMap::new(),
+            spend: 0.,
+        }
+    }
+}
+
+// limits from the config, daily usage is persisted so that restarting the bot doesn't reset it,
+// the per-minute message counts are only kept in memory
+pub struct Quotas {
+    path: PathBuf,
+    limits: LimitsConfig,
+    usage: DailyUsage,
+    user_messages: HashMap<UserId, VecDeque<Instant>>,
+}
+
+impl Quotas {
+    pub fn load(path: impl Into<PathBuf>, limits: LimitsConfig) -> Self {
+        let path = path.into();
+        let usage = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                println!("failed to parse quota file, starting over: {:?}", e);
+                DailyUsage::today()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => DailyUsage::today(),
+            Err(e) => {
+                println!("failed to read quota file, starting over: {:?}", e);
+                DailyUsage::today()
+            }
+        };
+        Self {
+            path,
+            limits,
+            usage,
+            user_messages: HashMap::new(),
+        }
+    }
+
+    fn save(&self) -> Result {
+        write_json_atomically(&self.path, &self.usage)
+    }
+
+    fn roll_over(&mut self) {
+        if self.usage.day != Utc::now().naive_utc().date() {
+            self.usage = DailyUsage::today();
+        }
+    }
+
+    // messages sent by the user in the last minute that asked for a reply
+    fn recent_messages(&mut self, user: UserId) -> &mut VecDeque<Instant> {
+        let now = Instant::now();
+        let messages = self.user_messages.entry(user).or_default();
+        while matches!(messages.front(), Some(&t) if now.duration_since(t) >= RATE_WINDOW) {
+            messages.pop_front();
+        }
+        messages
+    }
+
+    // counts the message against the user's rate if it's within the limit
+    pub fn check_user_message(&mut self, user: UserId) -> Result {
+        let limit = self.limits.user_messages_per_minute;
+        let messages = self.recent_messages(user);
+        if let Some(limit) = limit {
+            if messages.len() >= limit as usize {
+                Err(AppError::QuotaExceeded(QuotaLimit::UserMessages(limit)))?
+            }
+        }
+        messages.push_back(Instant::now());
+        Ok(())
+    }
+
+    // whether the chat and the bot as a whole still have budget left for a reply
+    pub fn check_budget(&mut self, chat: ChatId) -> Result {
+        self.roll_over();
+        if let Some(cap) = self.limits.daily_spend_cap {
+            if self.usage.spend >= cap {
+                Err(AppError::QuotaExceeded(QuotaLimit::DailySpend(cap)))?
+            }
+        }
+        if let Some(limit) = self.limits.chat_tokens_per_day {
+            if self.chat_tokens(chat) >= limit {
+                Err(AppError::QuotaExceeded(QuotaLimit::ChatTokens(limit)))?
+            }
+        }
+        Ok(())
+    }
+
+    pub fn record_usage(&mut self, chat: ChatId, tokens: u64, cost: f64) -> Result {
+        self.roll_over();
+        *self.usage.chat_tokens.entry(chat).or_default() += tokens;
+        self.usage.spend += cost;
+        self.save()
+    }
+
+    fn chat_tokens(&self, chat: ChatId) -> u64 {
+        self.usage.chat_tokens.get(&chat).copied().unwrap_or(0)
+    }
+
+    pub fn get_message_text(&mut self, chat: ChatId, user: Option<UserId>) -> String {
+        self.roll_over();
+        let of_limit = |used: String, limit: Option<String>| match limit {
+            Some(limit) => format!("{} of {}", used, limit),
+            None => format!("{} (unlimited)", used),
+        };
+
+        let mut lines = vec![
+            "Usage today (resets at midnight UTC)".to_string(),
+            format!(
+                "tokens in this chat: {}",
+                of_limit(
+                    self.chat_tokens(chat).to_string(),
+                    self.limits.chat_tokens_per_day.map(|l| l.to_string())
+                )
+            ),
+            format!(
+                "spending of the bot: {}",
+                of_limit(
+                    format!("${:.2}", self.usage.spend),
+                    self.limits.daily_spend_cap.map(|c| format!("${:.2}", c))
+                )
+            ),
+        ];
+        if let Some(user) = user {
+            let limit = self.limits.user_messages_per_minute;
+            let recent = self.recent_messages(user).len();
+            lines.push(format!(
+                "your messages in the last minute: {}",
+                of_limit(recent.to_string(), limit.map(|l| l.to_string()))
+            ));
+        }
+        lines.join("\n")
+    }
+}
@@ -1,4 +1,6 @@
-use crate::ChatId;
+use crate::completion::CompletionError;
+use crate::quota::QuotaLimit;
+use crate::{ChatId, UserId};
 use futures::io;
 use std::result;
 use teloxide::RequestError;
@@ -10,7 +12,8 @@ pub type Result<T = ()> = result::Result<T, Error>;
 pub enum Error {
     Request(RequestError),
     Io(io::Error),
-    Api(openai_api::Error),
+    Completion(CompletionError),
+    Serialization(serde_json::Error),
     App(AppError),
 }
 
@@ -26,15 +29,21 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Serialization(e)
+    }
+}
+
 impl From<AppError> for Error {
     fn from(e: AppError) -> Self {
         Self::App(e)
     }
 }
 
-impl From<openai_api::Error> for Error {
-    fn from(e: openai_api::Error) -> Self {
-        Self::Api(e)
+impl From<CompletionError> for Error {
+    fn from(e: CompletionError) -> Self {
+        Self::Completion(e)
     }
 }
 
@@ -46,4 +55,7 @@ pub enum AppError {
     MessageTooOld,
     UnexpectedCallbackQueryData(String),
     NoCallbackQueryData,
+    QuotaExceeded(QuotaLimit),
+    // None if the message didn't have a sender
+    NotAuthorized(ChatId, Option<UserId>),
 }